@@ -0,0 +1,257 @@
+//! Static rom analysis
+use crate::{device::ROM_LOAD_ADDR, util::*};
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+
+// amount of extended opcodes listed as evidence for detected variant
+const MAX_EVIDENCE: usize = 5;
+
+/// quirk settings which change behavior of some opcodes, one per `device::Quirks` field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quirk {
+    /// `8XY6`/`8XYE` copy vy into vx before shifting
+    UseVyWhenShifting,
+    /// `BNNN` jumps with offset from vx instead of v0
+    BxnnJumpWithOffset,
+    /// `FX55`/`FX65` advance index register
+    IncrementIregOnRegToMem,
+}
+
+impl Quirk {
+    pub const ALL: [Quirk; 3] = [
+        Quirk::UseVyWhenShifting,
+        Quirk::BxnnJumpWithOffset,
+        Quirk::IncrementIregOnRegToMem,
+    ];
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::UseVyWhenShifting => "shift uses vy (8XY6/8XYE)",
+            Self::BxnnJumpWithOffset => "jump with vx offset (BNNN)",
+            Self::IncrementIregOnRegToMem => "index increment on store/load (FX55/FX65)",
+        }
+    }
+}
+
+/// quirk which affects given instruction, if any
+pub fn quirk_of(inst: u16) -> Option<Quirk> {
+    match take_op(inst) {
+        0x8 if matches!(take_n(inst), 0x6 | 0xe) => Some(Quirk::UseVyWhenShifting),
+        0xb => Some(Quirk::BxnnJumpWithOffset),
+        0xf if matches!(take_nn(inst), 0x55 | 0x65) => Some(Quirk::IncrementIregOnRegToMem),
+        _ => None,
+    }
+}
+
+/// interpreter variants told apart by their extended opcodes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Variant {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Variant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Chip8 => "CHIP-8",
+            Self::SuperChip => "SUPER-CHIP",
+            Self::XoChip => "XO-CHIP",
+        }
+    }
+}
+
+/// variant which introduced given instruction, `None` for plain chip-8 ones
+pub fn extension_of(inst: u16) -> Option<Variant> {
+    match (take_op(inst), take_nn(inst)) {
+        // scroll down, scroll right/left, exit, lores/hires
+        (0x0, 0xc1..=0xcf | 0xfb..=0xff) if take_x(inst) == 0 => Some(Variant::SuperChip),
+        // scroll up
+        (0x0, 0xd0..=0xdf) if take_x(inst) == 0 => Some(Variant::XoChip),
+        // save/load register range
+        (0x5, _) if matches!(take_n(inst), 0x2 | 0x3) => Some(Variant::XoChip),
+        // 16x16 sprite
+        (0xd, _) if take_n(inst) == 0 => Some(Variant::SuperChip),
+        // long index load, audio pattern
+        (0xf, 0x00 | 0x02) if take_x(inst) == 0 => Some(Variant::XoChip),
+        // plane select, pitch
+        (0xf, 0x01 | 0x3a) => Some(Variant::XoChip),
+        // big font, flag storage
+        (0xf, 0x30 | 0x75 | 0x85) => Some(Variant::SuperChip),
+        _ => None,
+    }
+}
+
+/// decode every aligned pair of bytes as an instruction, code and data are interleaved
+/// so some of them are not real instructions, address is given along with instruction
+fn instructions(rom: &[u8]) -> impl Iterator<Item = (usize, u16)> + '_ {
+    rom.chunks_exact(2).enumerate().map(|(i, word)| {
+        let inst = ((word[0] as u16) << 8) | (word[1] as u16);
+        (ROM_LOAD_ADDR + i * 2, inst)
+    })
+}
+
+/// most extended variant whose opcodes appear in rom, with addresses of those opcodes
+pub fn detect_variant(rom: &[u8]) -> (Variant, Vec<(usize, u16)>) {
+    let mut variant = Variant::Chip8;
+    let mut evidence = Vec::new();
+    for (addr, inst) in instructions(rom) {
+        if let Some(v) = extension_of(inst) {
+            variant = variant.max(v);
+            evidence.push((addr, inst));
+        }
+    }
+    (variant, evidence)
+}
+
+/// count quirk-dependent instructions in rom
+pub fn count_quirk_opcodes(rom: &[u8]) -> Vec<(Quirk, usize)> {
+    let mut counts = Quirk::ALL.map(|q| (q, 0));
+    for (_, inst) in instructions(rom) {
+        if let Some(quirk) = quirk_of(inst) {
+            if let Some((_, n)) = counts.iter_mut().find(|(q, _)| *q == quirk) {
+                *n += 1;
+            }
+        }
+    }
+    counts.to_vec()
+}
+
+pub fn print_report(rom: &[u8]) {
+    let (variant, evidence) = detect_variant(rom);
+    println!("Detected variant: {}", variant.name());
+    if variant != Variant::Chip8 {
+        let listed = evidence
+            .iter()
+            .take(MAX_EVIDENCE)
+            .map(|(addr, inst)| format!("{:04x} at {:#05x}", inst, addr))
+            .collect::<Vec<_>>();
+        println!("  extended opcodes: {}", listed.join(", "));
+        println!(
+            "  {} opcodes aren't supported here, an interpreter for {} is suggested",
+            variant.name(),
+            variant.name()
+        );
+    }
+    println!("Quirks likely relevant for this rom:");
+    for (quirk, n) in count_quirk_opcodes(rom) {
+        let verdict = if n > 0 { "likely relevant" } else { "not used" };
+        println!(
+            "  {:<44} {:>4} occurrences, {}",
+            quirk.describe(),
+            n,
+            verdict
+        );
+    }
+}
+
+/// how execution continues after an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flow {
+    /// fall through to following instruction
+    Next,
+    /// unconditional jump
+    Jump(usize),
+    /// subroutine call, continues after it on return
+    Call(usize),
+    /// conditional skip of following instruction
+    Skip,
+    /// return from subroutine
+    Return,
+    /// `BNNN` jump with register offset, target unknown statically
+    Indirect(usize),
+}
+
+fn flow_of(inst: u16) -> Flow {
+    match take_op(inst) {
+        0x0 if inst == 0x00ee => Flow::Return,
+        0x1 => Flow::Jump(take_nnn(inst) as usize),
+        0x2 => Flow::Call(take_nnn(inst) as usize),
+        0x3 | 0x4 | 0x5 | 0x9 => Flow::Skip,
+        0xb => Flow::Indirect(take_nnn(inst) as usize),
+        0xe if matches!(take_nn(inst), 0x9e | 0xa1) => Flow::Skip,
+        _ => Flow::Next,
+    }
+}
+
+/// instruction at ram address, `None` outside rom
+fn inst_at(rom: &[u8], addr: usize) -> Option<u16> {
+    let offset = addr.checked_sub(ROM_LOAD_ADDR)?;
+    let word = rom.get(offset..offset + 2)?;
+    Some(((word[0] as u16) << 8) | (word[1] as u16))
+}
+
+/// addresses reachable from rom start and the ones starting basic blocks,
+/// `BNNN` targets depend on registers so walking stops there
+fn reachable(rom: &[u8]) -> (BTreeSet<usize>, BTreeSet<usize>) {
+    let mut visited = BTreeSet::new();
+    let mut leaders = BTreeSet::from([ROM_LOAD_ADDR]);
+    let mut pending = vec![ROM_LOAD_ADDR];
+    while let Some(addr) = pending.pop() {
+        let inst = match inst_at(rom, addr) {
+            Some(inst) if visited.insert(addr) => inst,
+            _ => continue,
+        };
+        let next = match flow_of(inst) {
+            Flow::Next => vec![addr + 2],
+            Flow::Jump(to) => vec![to],
+            Flow::Call(to) => vec![to, addr + 2],
+            Flow::Skip => vec![addr + 2, addr + 4],
+            Flow::Return | Flow::Indirect(_) => vec![],
+        };
+        if flow_of(inst) != Flow::Next {
+            leaders.extend(next.iter().copied());
+        }
+        pending.extend(next);
+    }
+    (visited, leaders)
+}
+
+/// write graphviz graph of basic blocks reachable from rom start and edges between them
+pub fn write_cfg_dot(rom: &[u8], out: &mut impl Write) -> io::Result<()> {
+    let (visited, leaders) = reachable(rom);
+    writeln!(out, "digraph cfg {{")?;
+    writeln!(out, "    node [shape=box fontname=monospace];")?;
+    for &start in leaders.iter().filter(|addr| visited.contains(addr)) {
+        // extend block until control flow changes or another block starts
+        let mut label = String::new();
+        let mut addr = start;
+        let (last, inst) = loop {
+            let inst = inst_at(rom, addr).unwrap_or_default();
+            label.push_str(&format!("{:#05x}  {:04x}\\l", addr, inst));
+            let next = addr + 2;
+            if flow_of(inst) != Flow::Next || leaders.contains(&next) || !visited.contains(&next) {
+                break (addr, inst);
+            }
+            addr = next;
+        };
+        writeln!(out, "    \"{:#05x}\" [label=\"{}\"];", start, label)?;
+        let edge = |out: &mut dyn Write, to: String, attrs: &str| {
+            writeln!(out, "    \"{:#05x}\" -> \"{}\"{};", start, to, attrs)
+        };
+        match flow_of(inst) {
+            Flow::Next if visited.contains(&(last + 2)) => {
+                edge(out, format!("{:#05x}", last + 2), "")?
+            }
+            Flow::Next | Flow::Return => {}
+            Flow::Jump(to) => edge(out, format!("{:#05x}", to), "")?,
+            Flow::Call(to) => {
+                edge(out, format!("{:#05x}", to), " [style=bold label=call]")?;
+                edge(out, format!("{:#05x}", last + 2), " [style=dotted]")?;
+            }
+            Flow::Skip => {
+                edge(out, format!("{:#05x}", last + 2), "")?;
+                edge(out, format!("{:#05x}", last + 4), " [label=skip]")?;
+            }
+            Flow::Indirect(base) => {
+                // one node per indirect jump, offset is only known at runtime
+                let to = format!("{:#05x} + vreg", base);
+                writeln!(out, "    \"{}\" [shape=ellipse style=dashed];", to)?;
+                edge(out, to, " [style=dashed]")?;
+            }
+        }
+    }
+    writeln!(out, "}}")
+}
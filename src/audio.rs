@@ -0,0 +1,78 @@
+//! Beeper driven by sound timer
+use crate::timing::Clock;
+use quad_snd::{AudioContext, PlaySoundParams, Sound};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// beep tone frequency in Hz
+pub const BEEP_FREQ: u32 = 440;
+/// loudness of beep, full scale is 1.0
+const BEEP_VOLUME: f32 = 0.2;
+// samples per second of generated tone
+const SAMPLE_RATE: u32 = 44100;
+// how often sound timer is checked, well below its 60 Hz rate
+const POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+/// play tone while sound timer is non-zero and emulator isn't paused, until shutdown
+pub fn beep_routine(
+    sound_timer: Arc<AtomicU8>,
+    shutdown: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>,
+) {
+    let ctx = AudioContext::new();
+    let tone = Sound::load(&ctx, &square_wave_wav(BEEP_FREQ));
+    let mut playing = false;
+    while !shutdown.load(Ordering::SeqCst) {
+        let beep = sound_timer.load(Ordering::SeqCst) > 0 && !paused.load(Ordering::SeqCst);
+        if beep && !playing {
+            tone.play(
+                &ctx,
+                PlaySoundParams {
+                    looped: true,
+                    volume: 1.0,
+                },
+            );
+        } else if !beep && playing {
+            tone.stop(&ctx);
+        }
+        playing = beep;
+        clock.sleep(POLL_INTERVAL);
+    }
+    tone.stop(&ctx);
+}
+
+/// one second of square wave as 16 bit mono wav, whole number of periods so it loops
+/// without clicks
+fn square_wave_wav(freq: u32) -> Vec<u8> {
+    let amplitude = (i16::MAX as f32 * BEEP_VOLUME) as i16;
+    let samples = (0..SAMPLE_RATE).map(|i| {
+        let high = (i as u64 * freq as u64 * 2 / SAMPLE_RATE as u64).is_multiple_of(2);
+        if high {
+            amplitude
+        } else {
+            -amplitude
+        }
+    });
+    let data_len = SAMPLE_RATE * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // format chunk size
+    wav.extend_from_slice(&1u16.to_le_bytes()); // pcm
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&2u16.to_le_bytes()); // bytes per sample
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    samples.for_each(|s| wav.extend_from_slice(&s.to_le_bytes()));
+    wav
+}
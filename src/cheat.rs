@@ -0,0 +1,77 @@
+//! Cheats forcing ram and register values while rom runs
+use crate::device::RAM_SIZE;
+use std::{fs, path::Path};
+
+/// values kept fixed during execution, loaded from cheat file
+///
+/// every line of file is either `ram <addr> <value>` or `v<x> <value>`, numbers are
+/// decimal or `0x` prefixed hex, addresses must be inside ram, empty lines and lines
+/// starting with `#` are skipped
+#[derive(Default)]
+pub struct Cheats {
+    /// ram patches applied once per frame
+    pub ram: Vec<(usize, u8)>,
+    /// register forces applied after every instruction
+    pub regs: Vec<(usize, u8)>,
+}
+
+impl Cheats {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&text)
+    }
+
+    /// cheats from contents of cheat file
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut cheats = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("Bad cheat on line {}: {}", i + 1, line);
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["ram", addr, val] => cheats.ram.push((
+                    parse_number(addr)
+                        .filter(|&addr| addr < RAM_SIZE)
+                        .ok_or_else(bad_line)?,
+                    parse_number(val).ok_or_else(bad_line)?,
+                )),
+                [reg, val] if reg.starts_with('v') => cheats.regs.push((
+                    usize::from_str_radix(&reg[1..], 16)
+                        .ok()
+                        .filter(|&x| x < 0x10)
+                        .ok_or_else(bad_line)?,
+                    parse_number(val).ok_or_else(bad_line)?,
+                )),
+                _ => return Err(bad_line()),
+            }
+        }
+        Ok(cheats)
+    }
+}
+
+/// parse decimal or `0x` prefixed hex number
+fn parse_number<T: TryFrom<u32>>(s: &str) -> Option<T> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    T::try_from(n).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_address_outside_ram_is_rejected() {
+        let cheats = Cheats::parse("v3 0x42\nram 0xfff 1\n").unwrap();
+        assert_eq!(cheats.ram, vec![(0xfff, 1)]);
+        assert_eq!(
+            Cheats::parse("v3 0x42\nram 0x1000 1\n").err().unwrap(),
+            "Bad cheat on line 2: ram 0x1000 1"
+        );
+    }
+}
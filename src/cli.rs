@@ -0,0 +1,274 @@
+//! Command line arguments
+use chip_8_emulator::{
+    device::{Chip8, Quirks, Strictness, DISPLAY_H, DISPLAY_W, IPS},
+    graphics::{InputConfig, RenderConfig},
+    trace::DEFAULT_TRACE_LEN,
+    util::{ExecError, Radix},
+};
+use std::{env, num::NonZeroU32, path::PathBuf, str::FromStr, time::Duration};
+
+/// placeholder rom run when no rom is given, shows "C8"
+pub const SPLASH_ROM: &[u8] = include_bytes!("../roms/splash.ch8");
+
+pub struct Args {
+    /// path to rom file to be executed
+    pub rom_path: Option<PathBuf>,
+    /// rom given inline as hex string, used instead of file
+    pub rom_hex: Option<Vec<u8>>,
+    /// collect frame and instruction timings, print histogram on exit
+    pub frame_histogram: bool,
+    /// presentation settings
+    pub render: RenderConfig,
+    /// key polling settings
+    pub input: InputConfig,
+    /// draw into back buffer shown on clear
+    pub buffered_display: bool,
+    /// cache decoded instructions by address
+    pub predecode: bool,
+    /// execute one instruction per step key press
+    pub step: bool,
+    /// interpreter behavior differences
+    pub quirks: Quirks,
+    /// reaction to unknown opcodes and out of range memory accesses
+    pub strictness: Strictness,
+    /// report every unknown opcode occurrence instead of each distinct one once
+    pub warn_every: bool,
+    /// warn when rom writes into font region
+    pub warn_font: bool,
+    /// warn when rom modifies its code ahead of pc
+    pub warn_smc: bool,
+    /// extra time added per ram access
+    pub ram_latency: Duration,
+    /// instructions per second
+    pub ips: NonZeroU32,
+    /// seed of `CXNN` random numbers, from entropy when missing
+    pub seed: Option<u64>,
+    /// enable non-standard opcodes
+    pub extensions: bool,
+    /// report panics in instruction handlers as execution errors
+    pub panic_guard: bool,
+    /// bit per opcode class that may be executed
+    pub allowed_ops: u16,
+    /// skip disallowed opcodes instead of stopping
+    pub skip_denied: bool,
+    /// warn when rom reads register before writing it
+    pub warn_uninit: bool,
+    /// keep keys released together for following key waits
+    pub queue_keys: bool,
+    /// display resolution as width and height
+    pub display_size: (usize, usize),
+    /// amount of recent instructions printed when execution fails
+    pub crash_trace: usize,
+    /// number base of values in debug output
+    pub radix: Radix,
+    /// file to log jumps, calls and returns to
+    pub cflog: Option<PathBuf>,
+    /// file for quicksave and quickload, defaults to rom path with `.state` extension
+    pub state_file: Option<PathBuf>,
+    /// file with ram and register values to force while running
+    pub cheats: Option<PathBuf>,
+    /// print registers and stack once execution stops
+    pub dump_final_state: bool,
+    /// include ram in final state dump
+    pub dump_final_ram: bool,
+    /// report which quirks rom likely depends on instead of running it
+    pub analyze: bool,
+    /// file to write control flow graph of rom to instead of running it
+    pub cfg_dot: Option<PathBuf>,
+    /// amount of instructions to run without window before printing final state
+    pub headless: Option<usize>,
+}
+
+pub fn parse_args() -> Args {
+    let mut rom_path = None;
+    let mut rom_hex = None;
+    let mut frame_histogram = false;
+    let mut analyze = false;
+    let mut headless = None;
+    let mut dump_final_state = false;
+    let mut dump_final_ram = false;
+    let mut cfg_dot = None;
+    let mut crash_trace = DEFAULT_TRACE_LEN;
+    let mut buffered_display = false;
+    let mut predecode = false;
+    let mut strictness = Strictness::Warn;
+    let mut quirks = Quirks::default();
+    let mut step = false;
+    let mut warn_every = false;
+    let mut warn_uninit = false;
+    let mut warn_font = false;
+    let mut warn_smc = false;
+    let mut panic_guard = false;
+    let mut extensions = false;
+    let mut allowed_ops = u16::MAX;
+    let mut ram_latency = Duration::ZERO;
+    let mut seed = None;
+    let mut ips = IPS;
+    let mut skip_denied = false;
+    let mut queue_keys = false;
+    let mut display_size = (DISPLAY_W, DISPLAY_H);
+    let mut cflog = None;
+    let mut cheats = None;
+    let mut state_file = None;
+    let mut radix = Radix::default();
+    let mut render = RenderConfig::default();
+    let mut input = InputConfig::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frame-histogram" => frame_histogram = true,
+            "--analyze" => analyze = true,
+            "--headless" => headless = Some(take_value(&mut args, "--headless")),
+            "--dump-final-state" => dump_final_state = true,
+            "--dump-final-ram" => (dump_final_state, dump_final_ram) = (true, true),
+            "--cfg-dot" => cfg_dot = Some(take_value(&mut args, "--cfg-dot")),
+            "--rom-hex" => {
+                let hex: String = take_value(&mut args, "--rom-hex");
+                rom_hex =
+                    Some(parse_hex(&hex).unwrap_or_else(|err| panic!("Bad --rom-hex: {}", err)));
+            }
+            "--buffered-display" => buffered_display = true,
+            "--predecode" => predecode = true,
+            "--step" => step = true,
+            "--quirks" => quirks = take_value(&mut args, "--quirks"),
+            "--strictness" => strictness = take_value(&mut args, "--strictness"),
+            "--warn-every" => warn_every = true,
+            "--warn-uninit" => warn_uninit = true,
+            "--warn-font" => warn_font = true,
+            "--warn-smc" => warn_smc = true,
+            "--allow" => {
+                let classes: String = take_value(&mut args, "--allow");
+                allowed_ops =
+                    parse_op_classes(&classes).expect("Opcode classes should look like 1,2,d");
+            }
+            "--deny" => {
+                let classes: String = take_value(&mut args, "--deny");
+                allowed_ops &=
+                    !parse_op_classes(&classes).expect("Opcode classes should look like 1,2,d");
+            }
+            "--skip-denied" => skip_denied = true,
+            "--panic-guard" => panic_guard = true,
+            "--extensions" => extensions = true,
+            "--seed" => seed = Some(take_value(&mut args, "--seed")),
+            "--ips" => ips = take_value(&mut args, "--ips"),
+            "--ram-latency" => {
+                ram_latency = Duration::from_micros(take_value(&mut args, "--ram-latency"))
+            }
+            "--queue-keys" => queue_keys = true,
+            "--display-size" => {
+                let size: String = take_value(&mut args, "--display-size");
+                display_size = parse_size(&size).expect("Display size should look like 64x32");
+            }
+            "--radix" => radix = take_value(&mut args, "--radix"),
+            "--cflog" => cflog = Some(take_value(&mut args, "--cflog")),
+            "--state-file" => state_file = Some(take_value(&mut args, "--state-file")),
+            "--cheats" => cheats = Some(take_value(&mut args, "--cheats")),
+            "--crash-trace" => crash_trace = take_value(&mut args, "--crash-trace"),
+            "--alpha" => render.alpha = take_value(&mut args, "--alpha"),
+            "--fade-style" => render.fade_style = take_value(&mut args, "--fade-style"),
+            "--hue-speed" => render.hue_speed = take_value(&mut args, "--hue-speed"),
+            "--trail-frames" => render.trail_frames = Some(take_value(&mut args, "--trail-frames")),
+            "--blank-every" => render.blank_every = Some(take_value(&mut args, "--blank-every")),
+            "--gamma" => render.gamma = take_value(&mut args, "--gamma"),
+            "--collision-flash" => render.collision_flash = true,
+            "--integer-scale" => render.integer_scale = true,
+            "--flip-h" => render.flip_h = true,
+            "--flip-v" => render.flip_v = true,
+            "--rotate-180" => (render.flip_h, render.flip_v) = (true, true),
+            "--max-fps" => render.max_fps = Some(take_value(&mut args, "--max-fps")),
+            "--key-hold" => {
+                input.key_hold = Duration::from_millis(take_value(&mut args, "--key-hold"))
+            }
+            flag if flag.starts_with("--") => panic!("Unknown argument: {}", flag),
+            path => rom_path = Some(PathBuf::from_str(path).expect("Malformed rom path")),
+        }
+    }
+
+    Args {
+        rom_path,
+        rom_hex,
+        frame_histogram,
+        render,
+        input,
+        buffered_display,
+        predecode,
+        step,
+        quirks,
+        strictness,
+        warn_every,
+        warn_uninit,
+        warn_font,
+        warn_smc,
+        ram_latency,
+        ips,
+        seed,
+        panic_guard,
+        extensions,
+        allowed_ops,
+        skip_denied,
+        queue_keys,
+        display_size,
+        crash_trace,
+        radix,
+        cflog,
+        cheats,
+        state_file,
+        dump_final_state,
+        dump_final_ram,
+        analyze,
+        headless,
+        cfg_dot,
+    }
+}
+
+impl Args {
+    /// rom bytes from inline hex or from file, placeholder rom if neither is given
+    pub fn read_rom(&self) -> Result<Vec<u8>, ExecError> {
+        match (&self.rom_hex, &self.rom_path) {
+            (Some(rom), _) => Ok(rom.clone()),
+            (None, Some(path)) => Chip8::read_rom_from_file(path),
+            (None, None) => Ok(SPLASH_ROM.to_vec()),
+        }
+    }
+}
+
+/// parse value following a flag
+fn take_value<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> T {
+    args.next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| panic!("Missing or malformed value for {}", flag))
+}
+
+/// parse `<width>x<height>`
+fn parse_size(s: &str) -> Option<(usize, usize)> {
+    let (w, h) = s.split_once('x')?;
+    let size = (w.parse().ok()?, h.parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+/// parse comma separated opcode classes given as hex digit of highest nibble into bitmask
+fn parse_op_classes(s: &str) -> Option<u16> {
+    s.split(',').try_fold(0, |mask, class| {
+        let op = u8::from_str_radix(class.trim(), 16)
+            .ok()
+            .filter(|&op| op < 0x10)?;
+        Some(mask | 1 << op)
+    })
+}
+
+/// decode string of hex digit pairs into bytes
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits ({})", s.len()));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .filter(|pair| pair.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("not a hex byte at position {}", i))
+        })
+        .collect()
+}
@@ -1,19 +1,30 @@
-use crate::util::*;
-use macroquad::prelude::KeyCode;
+use crate::{
+    cheat::Cheats,
+    savestate::SavedState,
+    timing::{Clock, SystemClock, TimeHistogram},
+    trace::{ControlFlowLog, TraceBuffer, DEFAULT_TRACE_LEN},
+    util::*,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
     io::{BufReader, Read, Write},
-    path::Path,
+    num::NonZeroU32,
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, AtomicU8, Ordering},
+        atomic::{AtomicBool, AtomicU16, AtomicU8, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-    thread::{self},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-/// display dimensions
+/// default display dimensions, actual ones are stored in `Display`
 pub const DISPLAY_H: usize = 32;
 pub const DISPLAY_W: usize = 64;
 
@@ -24,34 +35,125 @@ pub const FONT_LOAD_ADDR: usize = 0x50;
 pub const ROM_LOAD_ADDR: usize = 0x200;
 
 /// chip-8 specifications
-pub const DISPLAY_SIZE: usize = DISPLAY_H * DISPLAY_W;
 pub const STACK_SIZE: usize = 16;
 pub const VREG_SIZE: usize = 16;
 pub const RAM_SIZE: usize = 4096;
 
-/// timing, instructions per second
-pub const IPS: usize = 700;
+/// timing, default instructions per second
+pub const IPS: NonZeroU32 = NonZeroU32::new(700).unwrap();
+
+/// speedup of instructions per second while turbo is held
+pub const TURBO_FACTOR: NonZeroU32 = NonZeroU32::new(10).unwrap();
 
 /// timers frequency, 60 Hz
 pub const TIMERS_FREQ: usize = 60;
 
-pub const USE_VY_WHEN_SHIFTING: bool = false; // TODO: should be a runtime setting
-pub const BXNN_JUMP_WITH_OFFSET: bool = false; // TODO: should be a runtime setting
-pub const INCREMENT_IREG_ON_REG_TO_MEM: bool = false; // TODO: should be a runtime setting
+/// how often paused loops check whether they should resume
+pub const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// behavior differences between interpreters, defaults match this emulator's original
+/// hardcoded behavior
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` copy vy into vx before shifting
+    pub use_vy_when_shifting: bool,
+    /// `BNNN` jumps with offset from vx instead of v0
+    pub bxnn_jump_with_offset: bool,
+    /// `FX55`/`FX65` advance index register
+    pub increment_ireg_on_reg_to_mem: bool,
+}
+
+impl Quirks {
+    /// original COSMAC VIP interpreter
+    pub fn cosmac() -> Self {
+        Self {
+            use_vy_when_shifting: true,
+            bxnn_jump_with_offset: false,
+            increment_ireg_on_reg_to_mem: true,
+        }
+    }
+
+    /// SUPER-CHIP 1.1 on HP48
+    pub fn superchip() -> Self {
+        Self {
+            use_vy_when_shifting: false,
+            bxnn_jump_with_offset: true,
+            increment_ireg_on_reg_to_mem: false,
+        }
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::default()),
+            "cosmac" => Ok(Self::cosmac()),
+            "superchip" => Ok(Self::superchip()),
+            _ => Err(format!("Unknown quirks preset: {}", s)),
+        }
+    }
+}
+
+/// handling of unknown opcodes and out of range memory accesses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    /// continue silently
+    Lenient,
+    /// log and continue
+    Warn,
+    /// stop with error
+    Strict,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lenient" => Ok(Self::Lenient),
+            "warn" => Ok(Self::Warn),
+            "strict" => Ok(Self::Strict),
+            _ => Err(format!("Unknown strictness: {}", s)),
+        }
+    }
+}
+
+/// execution status of device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineState {
+    /// executing or ready to execute
+    Running,
+    /// execution suspended by `paused` flag, resumes where it left off
+    Paused,
+    /// stopped on request, e.g. window was closed
+    Halted,
+    /// stopped because instruction failed
+    Error(ExecError),
+}
 
 pub struct Chip8 {
-    /// 64x32 display, 8-bit depth
-    pub display: Arc<Mutex<[u8; DISPLAY_SIZE]>>,
+    /// 64x32 display by default, 8-bit depth
+    pub display: Arc<Mutex<Display>>,
     /// program counter
     pub pc: u16,
     /// index register
     pub ireg: u16,
     /// subroutine stack
     pub stack: [u16; STACK_SIZE],
-    /// stack pointer
-    pub sp: i8,
+    /// stack pointer, number of occupied stack slots, zero when empty
+    pub sp: usize,
     /// variable registers
     pub vreg: [u8; VREG_SIZE],
+    /// bit per register, set once register was written
+    pub written_vregs: u16,
+    /// warn when register is read before being written
+    pub warn_uninit_reads: bool,
+    /// warn when rom writes into font region
+    pub warn_font_writes: bool,
+    /// warn when rom writes into its own code ahead of current instruction
+    pub warn_self_modify: bool,
     /// 4 kb of random access memory
     pub ram: [u8; RAM_SIZE],
     /// delay timer, decrements at 60 Hz rate
@@ -62,64 +164,479 @@ pub struct Chip8 {
     pub down_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
     /// keys that were release during current frame
     pub released_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
-    /// keymap for mapping from internal keys to macroquad
-    pub keymap: HashMap<Chip8Key, KeyCode>,
+    /// off-screen display drawn to instead of `display` when buffered display is used
+    pub back_buffer: Option<Display>,
+    /// time back buffer was last shown
+    pub last_present: Duration,
+    /// interpreter behavior differences honored while executing
+    pub quirks: Quirks,
+    /// reaction to unknown opcodes and out of range memory accesses
+    pub strictness: Strictness,
+    /// source of `CXNN` random numbers, seeded from entropy unless `reseed` is called
+    rng: StdRng,
+    /// bit per opcode class (highest nibble) that may be executed, all set by default
+    pub allowed_ops: u16,
+    /// skip instructions outside `allowed_ops` instead of stopping with error
+    pub skip_denied: bool,
+    /// enable non-standard opcodes, currently `00BN` display readback
+    pub extensions: bool,
+    /// turn panics while executing instruction into `ExecError::InternalPanic`
+    pub panic_guard: bool,
+    /// warn about each distinct unknown opcode only once instead of every occurrence
+    pub warn_once: bool,
+    /// unknown opcodes already reported
+    pub warned_opcodes: HashSet<u16>,
+    /// unknown opcode occurrences not reported because of `warn_once`
+    pub repeated_warnings: u64,
+    /// keep keys released together for following `FX0A` instead of dropping them
+    pub queue_key_releases: bool,
+    /// released keys not yet consumed by `FX0A`
+    pub queued_releases: VecDeque<Chip8Key>,
+    /// key that last satisfied `FX0A`, reported up by skips until released
+    pub consumed_key: Option<Chip8Key>,
+    /// copy of program counter readable from other threads
+    pub shared_pc: Arc<AtomicU16>,
+    /// copy of index register readable from other threads
+    pub shared_ireg: Arc<AtomicU16>,
+    /// size of loaded rom in bytes
+    pub rom_size: usize,
+    /// set when emulator is closing, stops execution and timers
+    pub shutdown: Arc<AtomicBool>,
+    /// stops execution and timers while set, resumes where it left off when cleared
+    pub paused: Arc<AtomicBool>,
+    /// execute only requested instructions, one per `step_requests` increment
+    pub step_mode: bool,
+    /// instructions frontend asked to execute in step mode
+    pub step_requests: Arc<AtomicUsize>,
+    /// set by frontend to have device do `warm_reset` before next instruction
+    pub reset_requested: Arc<AtomicBool>,
+    /// instructions per second
+    pub ips: NonZeroU32,
+    /// set by frontend while execution should run `TURBO_FACTOR` times faster
+    pub turbo: Arc<AtomicBool>,
+    /// set by frontend to have device save state to `state_path` before next instruction
+    pub save_requested: Arc<AtomicBool>,
+    /// set by frontend to have device load state from `state_path` before next instruction
+    pub load_requested: Arc<AtomicBool>,
+    /// file for quicksave and quickload
+    pub state_path: Option<PathBuf>,
+    /// set whenever sprite drawing sets vf, cleared by frontend showing it
+    pub collision: Arc<AtomicBool>,
+    /// execution status, shared so frontends can report when device stops
+    pub state: Arc<Mutex<MachineState>>,
+    /// ram reads and writes done so far, instruction fetches included
+    pub ram_accesses: Cell<u64>,
+    /// extra time every ram access adds to instruction, experimental and zero by default
+    pub ram_latency: Duration,
+    /// time source pacing execution, shared with timers so both follow same time
+    pub clock: Arc<dyn Clock>,
+    /// optional instruction timings, collected only when enabled
+    pub inst_histogram: Option<TimeHistogram>,
+    /// called after every executed instruction with read-only device state,
+    /// runs inside the timed loop so a slow callback lowers the effective IPS
+    pub on_cycle: Option<CycleHook>,
+    /// decoded instructions by address, `None` when pre-decode cache is off,
+    /// entries are dropped on writes through `ram_write` or state load
+    pub predecoded: Option<Vec<Option<Decoded>>>,
+    /// optional memory mapped io, reads in range are answered by handler instead of ram,
+    /// unset by default so standard roms see plain ram
+    pub mmio: Option<(Range<usize>, MmioHandler)>,
+    /// ram and register values forced while running
+    pub cheats: Option<Cheats>,
+    /// time ram cheats were last applied
+    pub last_cheat_frame: Duration,
+    /// last executed instructions, dumped when execution fails
+    pub recent: TraceBuffer,
+    /// address of instruction executed last, reported if it fails
+    pub inst_addr: u16,
+    /// number base of values in debug output
+    pub radix: Radix,
+    /// optional log of executed jumps, calls and returns
+    pub cflog: Option<ControlFlowLog>,
 }
 
 type EE = ExecError;
 
+/// per-instruction callback for embedders, see `Chip8::on_cycle`
+pub type CycleHook = Box<dyn FnMut(&Chip8) + Send>;
+
+/// handler producing byte read from memory mapped address, see `Chip8::mmio`
+pub type MmioHandler = Box<dyn Fn(usize) -> u8 + Send>;
+
 /// control flow
 impl Chip8 {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, ExecError> {
-        let default_keymap = get_default_keymap(); // TODO: move out keymap outside device
+        Self::from_bytes(Self::read_rom_from_file(path)?)
+    }
+
+    /// create device with rom given as bytes instead of file
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, ExecError> {
         let mut device = Self {
-            display: Arc::new(Mutex::new([0; DISPLAY_SIZE])),
+            display: Arc::new(Mutex::new(Display::new(DISPLAY_W, DISPLAY_H))),
             pc: ROM_LOAD_ADDR as u16,
             ireg: 0,
             stack: [0; STACK_SIZE],
-            sp: -1,
+            sp: 0,
             vreg: [0; VREG_SIZE],
+            written_vregs: 0,
+            warn_uninit_reads: false,
+            warn_font_writes: false,
+            warn_self_modify: false,
             ram: [0; RAM_SIZE],
             delay_timer: Arc::new(AtomicU8::new(0)),
             sound_timer: Arc::new(AtomicU8::new(0)),
-            down_keys: default_keymap
-                .keys()
-                .map(|&k| (k, Arc::new(AtomicBool::from(false))))
+            down_keys: (0..0x10)
+                .map(|k| (Chip8Key::from(k), Arc::new(AtomicBool::from(false))))
                 .collect(),
-            released_keys: default_keymap
-                .keys()
-                .map(|&k| (k, Arc::new(AtomicBool::from(false))))
+            released_keys: (0..0x10)
+                .map(|k| (Chip8Key::from(k), Arc::new(AtomicBool::from(false))))
                 .collect(),
-            keymap: default_keymap,
+            back_buffer: None,
+            last_present: Duration::ZERO,
+            quirks: Quirks::default(),
+            strictness: Strictness::Warn,
+            rng: StdRng::from_entropy(),
+            allowed_ops: u16::MAX,
+            skip_denied: false,
+            extensions: false,
+            panic_guard: false,
+            warn_once: true,
+            warned_opcodes: HashSet::new(),
+            repeated_warnings: 0,
+            queue_key_releases: false,
+            queued_releases: VecDeque::new(),
+            consumed_key: None,
+            shared_pc: Arc::new(AtomicU16::new(ROM_LOAD_ADDR as u16)),
+            shared_ireg: Arc::new(AtomicU16::new(0)),
+            rom_size: 0,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            step_mode: false,
+            step_requests: Arc::new(AtomicUsize::new(0)),
+            reset_requested: Arc::new(AtomicBool::new(false)),
+            ips: IPS,
+            turbo: Arc::new(AtomicBool::new(false)),
+            save_requested: Arc::new(AtomicBool::new(false)),
+            load_requested: Arc::new(AtomicBool::new(false)),
+            state_path: None,
+            collision: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(Mutex::new(MachineState::Running)),
+            ram_accesses: Cell::new(0),
+            ram_latency: Duration::ZERO,
+            clock: Arc::new(SystemClock::new()),
+            inst_histogram: None,
+            on_cycle: None,
+            predecoded: None,
+            mmio: None,
+            cheats: None,
+            last_cheat_frame: Duration::ZERO,
+            recent: TraceBuffer::new(DEFAULT_TRACE_LEN),
+            inst_addr: ROM_LOAD_ADDR as u16,
+            radix: Radix::Hex,
+            cflog: None,
         };
-        let rom = Self::read_rom_from_file(path)?;
+        device.rom_size = rom.len();
         device.load(rom, ROM_LOAD_ADDR)?;
         device.load(get_default_font(), FONT_LOAD_ADDR)?;
 
         Ok(device)
     }
 
+    /// execute until shutdown, dump recent instructions on error
     pub fn run(&mut self) -> Result<(), ExecError> {
-        let time_per_instruction = Duration::from_secs(1) / IPS as u32;
+        *self.state.lock().unwrap() = MachineState::Running;
+        let result = self.run_loop();
+        self.finish(result)
+    }
+
+    /// execute up to `max_instructions` as fast as possible without frontend threads,
+    /// timers tick every `ips / TIMERS_FREQ` instructions so runs are repeatable
+    pub fn run_headless(&mut self, max_instructions: usize) -> Result<(), ExecError> {
+        *self.state.lock().unwrap() = MachineState::Running;
+        let ticks_every = (self.ips.get() as usize / TIMERS_FREQ).max(1);
+        let mut result = Ok(());
+        for executed in 1..=max_instructions {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            // ram cheats follow timer ticks so runs stay repeatable
+            let new_frame = executed.is_multiple_of(ticks_every);
+            if let Err(err) = self.cycle(new_frame) {
+                result = Err(err);
+                break;
+            }
+            if new_frame {
+                for timer in [&self.delay_timer, &self.sound_timer] {
+                    let _ = timer
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |t| t.checked_sub(1));
+                }
+            }
+        }
+        self.present();
+        self.finish(result)
+    }
+
+    /// report how execution ended and keep it in device state
+    fn finish(&mut self, result: Result<(), ExecError>) -> Result<(), ExecError> {
+        if let Some(cflog) = self.cflog.as_mut() {
+            if let Err(err) = cflog.flush() {
+                eprintln!("Can't write control flow log: {}", err);
+            }
+        }
+        if self.repeated_warnings > 0 {
+            println!(
+                "{} unknown opcodes ({} repeated occurrences not reported)",
+                self.warned_opcodes.len(),
+                self.repeated_warnings
+            );
+        }
+        *self.state.lock().unwrap() = match result {
+            Ok(()) => MachineState::Halted,
+            Err(err) => {
+                eprintln!("Execution failed at {:#06x}: {}", self.inst_addr, err);
+                self.recent.dump(self.radix);
+                MachineState::Error(err)
+            }
+        };
+        result
+    }
+
+    /// fetch and execute single instruction, gives executed instruction
+    fn step(&mut self) -> Result<u16, ExecError> {
+        self.inst_addr = self.pc;
+        let decoded = self.fetch_decoded()?;
+        self.recent.record(self.inst_addr, decoded.inst);
+        self.execute(decoded)?;
+        Ok(decoded.inst)
+    }
+
+    /// execute one instruction along with per-instruction work shared by run modes:
+    /// key and cheat updates, control flow log and `on_cycle`, gives executed instruction,
+    /// `new_frame` tells whether a 60 Hz frame started, applying ram cheats
+    fn cycle(&mut self, new_frame: bool) -> Result<u16, ExecError> {
+        let inst = if self.panic_guard {
+            self.guarded_step()?
+        } else {
+            self.step()?
+        };
+        self.update_consumed_key();
+        self.apply_cheats(new_frame)?;
+        if let Some(cflog) = self.cflog.as_mut() {
+            if let Err(err) = cflog.record(self.inst_addr, inst, self.pc) {
+                eprintln!("Control flow logging stopped: {}", err);
+                self.cflog = None;
+            }
+        }
+        if let Some(mut on_cycle) = self.on_cycle.take() {
+            on_cycle(self);
+            self.on_cycle = Some(on_cycle);
+        }
+        Ok(inst)
+    }
+
+    /// in step mode consume one requested step, gives whether instruction may run
+    fn take_step_request(&self) -> bool {
+        !self.step_mode
+            || self
+                .step_requests
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+    }
+
+    /// print instruction executed in step mode along with registers after it
+    fn print_step(&self, inst_addr: u16, inst: u16) {
+        let r = |v: u16| self.radix.format(v);
+        let vreg = self.vreg.iter().map(|&v| r(v as u16)).collect::<Vec<_>>();
+        println!(
+            "{}: {}  pc: {}  ireg: {}  v: [{}]",
+            r(inst_addr),
+            r(inst),
+            r(self.pc),
+            r(self.ireg),
+            vreg.join(", ")
+        );
+    }
+
+    /// `step` which reports panic in instruction handler as error instead of unwinding
+    fn guarded_step(&mut self) -> Result<u16, ExecError> {
+        let pc = self.pc;
+        panic::catch_unwind(AssertUnwindSafe(|| self.step())).unwrap_or(Err(EE::InternalPanic(pc)))
+    }
+
+    /// restart `CXNN` random sequence, same seed gives same numbers
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// restart rom without reloading it: registers, stack, timers and pending keys are
+    /// cleared and pc is set to entry point, ram (with any code rom modified) and display
+    /// are left as they are
+    pub fn warm_reset(&mut self) {
+        self.pc = ROM_LOAD_ADDR as u16;
+        self.ireg = 0;
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.vreg = [0; VREG_SIZE];
+        self.written_vregs = 0;
+        self.delay_timer.store(0, Ordering::SeqCst);
+        self.sound_timer.store(0, Ordering::SeqCst);
+        self.queued_releases.clear();
+        self.consumed_key = None;
+    }
+
+    /// snapshot of registers, memory, display and timers
+    pub fn save_state(&self) -> SavedState {
+        let display = match self.back_buffer.as_ref() {
+            Some(back) => back.clone(),
+            None => self.display.lock().unwrap().clone(),
+        };
+        SavedState {
+            pc: self.pc,
+            ireg: self.ireg,
+            stack: self.stack.to_vec(),
+            sp: self.sp,
+            vreg: self.vreg,
+            ram: self.ram.to_vec(),
+            display,
+            delay_timer: self.delay_timer.load(Ordering::SeqCst),
+            sound_timer: self.sound_timer.load(Ordering::SeqCst),
+        }
+    }
+
+    /// continue from snapshot taken by `save_state`, pending key waits are dropped,
+    /// state must pass `SavedState::validate` as states from `read_from_file` do
+    pub fn load_state(&mut self, state: SavedState) {
+        self.pc = state.pc;
+        self.ireg = state.ireg;
+        self.stack.copy_from_slice(&state.stack);
+        self.sp = state.sp;
+        self.vreg = state.vreg;
+        self.written_vregs = u16::MAX;
+        self.ram.copy_from_slice(&state.ram);
+        if let Some(cache) = self.predecoded.as_mut() {
+            cache.fill(None);
+        }
+        if self.back_buffer.is_some() {
+            self.back_buffer = Some(state.display.clone());
+        }
+        *self.display.lock().unwrap() = state.display;
+        self.delay_timer.store(state.delay_timer, Ordering::SeqCst);
+        self.sound_timer.store(state.sound_timer, Ordering::SeqCst);
+        self.queued_releases.clear();
+        self.consumed_key = None;
+    }
+
+    /// carry out reset, save and load asked for by frontend
+    fn handle_requests(&mut self) {
+        if self.reset_requested.swap(false, Ordering::SeqCst) {
+            self.warm_reset();
+        }
+        let save = self.save_requested.swap(false, Ordering::SeqCst);
+        let load = self.load_requested.swap(false, Ordering::SeqCst);
+        if !save && !load {
+            return;
+        }
+        let path = match self.state_path.clone() {
+            Some(path) => path,
+            None => {
+                println!("No state file to save to or load from, see --state-file");
+                return;
+            }
+        };
+        if save {
+            match self.save_state().write_to_file(&path) {
+                Ok(()) => println!("State saved to {}", path.display()),
+                Err(err) => eprintln!("Can't save state to {}: {}", path.display(), err),
+            }
+        }
+        if load {
+            match SavedState::read_from_file(&path) {
+                Ok(state) => {
+                    self.load_state(state);
+                    println!("State loaded from {}", path.display());
+                }
+                Err(err) => eprintln!("Can't load state from {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    /// print registers and stack, and whole ram if `with_ram` is set
+    pub fn dump_state(&self, with_ram: bool) {
+        let r = |v: u16| self.radix.format(v);
+        println!(
+            "pc: {}  ireg: {}  sp: {}",
+            r(self.pc),
+            r(self.ireg),
+            self.sp
+        );
+        for (x, &v) in self.vreg.iter().enumerate() {
+            println!("  v{:X}: {}", x, r(v as u16));
+        }
+        let stack = self.stack[..self.sp]
+            .iter()
+            .map(|&a| r(a))
+            .collect::<Vec<_>>();
+        println!("stack: [{}]", stack.join(", "));
+        if with_ram {
+            for (i, row) in self.ram.chunks(16).enumerate() {
+                let bytes = row.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+                println!("{:#06x}: {}", i * 16, bytes.join(" "));
+            }
+        }
+    }
+
+    /// execution status, `Paused` while running device has `paused` set
+    pub fn state(&self) -> MachineState {
+        match *self.state.lock().unwrap() {
+            MachineState::Running if self.paused.load(Ordering::SeqCst) => MachineState::Paused,
+            state => state,
+        }
+    }
+
+    fn run_loop(&mut self) -> Result<(), ExecError> {
         // start timer threads
-        // start exit handler thread
         // optional: start display dimmer thread
-        loop {
-            let clock = Instant::now();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            self.handle_requests();
+            if self.paused.load(Ordering::SeqCst) || !self.take_step_request() {
+                self.clock.sleep(PAUSE_POLL_INTERVAL);
+                continue;
+            }
+            let start = self.clock.now();
+            let accesses_before = self.ram_accesses.get();
             // execute instruction cycle
-            let inst = self.fetch()?;
-            self.decode_and_execute(inst)?;
+            let new_frame = self.cheat_frame_due();
+            let inst = self.cycle(new_frame)?;
+            if self.step_mode {
+                self.print_step(self.inst_addr, inst);
+            }
+            self.shared_pc.store(self.pc, Ordering::Relaxed);
+            self.shared_ireg.store(self.ireg, Ordering::Relaxed);
+            self.present_if_stale();
             // wait to meet timing
-            let inst_time = clock.elapsed();
-            if let Some(sleep_time) = time_per_instruction.checked_sub(inst_time) {
-                thread::sleep(sleep_time);
+            let ips = if self.turbo.load(Ordering::Relaxed) {
+                self.ips.saturating_mul(TURBO_FACTOR)
+            } else {
+                self.ips
+            };
+            let time_per_instruction = Duration::from_secs(1) / ips.get();
+            let inst_time = self.clock.now().saturating_sub(start);
+            if let Some(histogram) = self.inst_histogram.as_mut() {
+                histogram.record(inst_time);
+            }
+            // simulated memory latency makes instructions touching more ram take longer
+            let accesses = self.ram_accesses.get() - accesses_before;
+            let inst_cost = time_per_instruction + self.ram_latency * accesses as u32;
+            if let Some(sleep_time) = inst_cost.checked_sub(inst_time) {
+                self.clock.sleep(sleep_time);
             } else {
                 println!("Instruction took longer than expected: {:#06x}", inst);
             }
         }
+        Ok(())
     }
 
-    fn read_rom_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, ExecError> {
+    pub fn read_rom_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, ExecError> {
         let file = File::open(path.as_ref()).map_err(|_| EE::LoadRomError)?;
         let mut reader = BufReader::new(file);
         let mut buffer = Vec::new();
@@ -129,261 +646,304 @@ impl Chip8 {
         Ok(buffer)
     }
 
-    fn fetch(&mut self) -> Result<u16, ExecError> {
-        let a = *self.ram.get(self.pc as usize).ok_or(EE::MemoryError)?;
-        let b = *self
-            .ram
-            .get((self.pc + 1) as usize)
-            .ok_or(EE::MemoryError)?;
-        self.pc += 2;
+    /// read instruction at `pc` and advance past it
+    pub fn fetch(&mut self) -> Result<u16, ExecError> {
+        // address math in usize, pc near u16::MAX must fail instead of overflowing
+        let addr = self.pc as usize;
+        let a = self.peek(addr).ok_or(EE::MemoryError)?;
+        let b = self.peek(addr + 1).ok_or(EE::MemoryError)?;
+        // memory mapped io may serve addresses up to u16::MAX
+        self.pc = self.pc.checked_add(2).ok_or(EE::MemoryError)?;
         Ok(((a as u16) << 8) | (b as u16))
     }
 
-    fn decode_and_execute(&mut self, inst: u16) -> Result<(), ExecError> {
-        // println!("Got instruction: {:#06x}", inst);
-        match take_op(inst) {
-            0x0 => match take_nnn(inst) {
-                // clear screen
-                0x0E0 => self.clear_display(),
-                // return from subroutine
-                0x0EE => self.pc = self.stack_pop()?,
-                _ => (),
-            },
+    /// `fetch` and decode, served from pre-decode cache when enabled
+    fn fetch_decoded(&mut self) -> Result<Decoded, ExecError> {
+        let addr = self.pc as usize;
+        let cached = self
+            .predecoded
+            .as_ref()
+            .and_then(|c| c.get(addr).copied().flatten());
+        if let Some(decoded) = cached {
+            // still counts as two ram reads so simulated latency doesn't change
+            self.ram_accesses.set(self.ram_accesses.get() + 2);
+            self.pc = self.pc.checked_add(2).ok_or(EE::MemoryError)?;
+            return Ok(decoded);
+        }
+        let decoded = Decoded::new(self.fetch()?);
+        // memory mapped bytes may change without write, never cache them
+        let mapped = self
+            .mmio
+            .as_ref()
+            .is_some_and(|(range, _)| range.contains(&addr) || range.contains(&(addr + 1)));
+        if let Some(entry) = self.predecoded.as_mut().and_then(|c| c.get_mut(addr)) {
+            if !mapped {
+                *entry = Some(decoded);
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// execute single fetched instruction, timers and input are driven from outside
+    pub fn decode_and_execute(&mut self, inst: u16) -> Result<(), ExecError> {
+        self.execute(Decoded::new(inst))
+    }
+
+    /// execute instruction already split into operands
+    fn execute(&mut self, d: Decoded) -> Result<(), ExecError> {
+        // println!("Got instruction: {:#06x}", d.inst);
+        if self.allowed_ops & (1 << d.op) == 0 {
+            return if self.skip_denied {
+                Ok(())
+            } else {
+                Err(EE::DeniedInstruction(d.inst))
+            };
+        }
+        match d.op {
+            0x0 => self.exec_0(d)?,
             // jump
             0x1 => {
-                self.pc = take_nnn(inst);
+                self.pc = d.nnn;
             }
             // subroutine call
             0x2 => {
                 self.stack_push(self.pc)?;
-                self.pc = take_nnn(inst);
+                self.pc = d.nnn;
             }
             // conditional skip when vx equal nn
             0x3 => {
-                if self.vx(inst)? == take_nn(inst) {
-                    self.skip_inst();
+                if self.vx(d)? == d.nn {
+                    self.skip_inst()?;
                 }
             }
             // conditional skip when vx not equal nn
             0x4 => {
-                if self.vx(inst)? != take_nn(inst) {
-                    self.skip_inst();
+                if self.vx(d)? != d.nn {
+                    self.skip_inst()?;
                 }
             }
             // conditional skip when vx equal vy
             0x5 => {
-                if take_n(inst) == 0 {
-                    if self.vx(inst)? == self.vy(inst)? {
-                        self.skip_inst();
+                if d.n == 0 {
+                    if self.vx(d)? == self.vy(d)? {
+                        self.skip_inst()?;
                     }
                 } else {
-                    Self::unknown(inst);
+                    self.unknown(d.inst)?;
                 }
             }
             // set register vx to nn
             0x6 => {
-                *self.vx_mut(inst)? = take_nn(inst);
+                *self.vx_mut(d)? = d.nn;
             }
             // add nn to register vx, allow overflow
             0x7 => {
-                let (val, _overflow) = self.vx(inst)?.overflowing_add(take_nn(inst));
-                *self.vx_mut(inst)? = val;
+                let (val, _overflow) = self.vx(d)?.overflowing_add(d.nn);
+                *self.vx_mut(d)? = val;
             }
             // logical and arithmetic operations
             0x8 => {
-                match take_n(inst) {
+                match d.n {
                     // vx = vy
-                    0x0 => *self.vx_mut(inst)? = self.vy(inst)?,
+                    0x0 => *self.vx_mut(d)? = self.vy(d)?,
                     // vx = vx OR vy
-                    0x1 => *self.vx_mut(inst)? = self.vx(inst)? | self.vy(inst)?,
+                    0x1 => *self.vx_mut(d)? = self.vx(d)? | self.vy(d)?,
                     // vx = vx AND vy
-                    0x2 => *self.vx_mut(inst)? = self.vx(inst)? & self.vy(inst)?,
+                    0x2 => *self.vx_mut(d)? = self.vx(d)? & self.vy(d)?,
                     // vx = vx XOR vy
-                    0x3 => *self.vx_mut(inst)? = self.vx(inst)? ^ self.vy(inst)?,
+                    0x3 => *self.vx_mut(d)? = self.vx(d)? ^ self.vy(d)?,
                     // vx = vx + vy, set vf on overflow
                     0x4 => {
-                        let (val, overflow) = self.vx(inst)?.overflowing_add(self.vy(inst)?);
-                        *self.vx_mut(inst)? = val;
+                        let (val, overflow) = self.vx(d)?.overflowing_add(self.vy(d)?);
+                        *self.vx_mut(d)? = val;
                         *self.vf_mut()? = if overflow { 0x1 } else { 0x0 };
                     }
                     // vx = vx - vy, unset vf on overflow
                     0x5 => {
-                        let (val, underflow) = self.vx(inst)?.overflowing_sub(self.vy(inst)?);
-                        *self.vx_mut(inst)? = val;
+                        let (val, underflow) = self.vx(d)?.overflowing_sub(self.vy(d)?);
+                        *self.vx_mut(d)? = val;
                         *self.vf_mut()? = if underflow { 0x0 } else { 0x1 };
                     }
                     // right shift
                     0x6 => {
-                        if USE_VY_WHEN_SHIFTING {
-                            *self.vx_mut(inst)? = self.vy(inst)?;
+                        if self.quirks.use_vy_when_shifting {
+                            *self.vx_mut(d)? = self.vy(d)?;
                         }
-                        let shifted_bit = self.vx(inst)? & 0x1;
-                        *self.vx_mut(inst)? >>= 1;
+                        let shifted_bit = self.vx(d)? & 0x1;
+                        *self.vx_mut(d)? >>= 1;
                         *self.vf_mut()? = shifted_bit;
                     }
                     // vx = vy - vx, unset vf on overflow
                     0x7 => {
-                        let (val, underflow) = self.vy(inst)?.overflowing_sub(self.vx(inst)?);
-                        *self.vx_mut(inst)? = val;
+                        let (val, underflow) = self.vy(d)?.overflowing_sub(self.vx(d)?);
+                        *self.vx_mut(d)? = val;
                         *self.vf_mut()? = if underflow { 0x0 } else { 0x1 };
                     }
                     // left shift
                     0xe => {
-                        if USE_VY_WHEN_SHIFTING {
-                            *self.vx_mut(inst)? = self.vy(inst)?;
+                        if self.quirks.use_vy_when_shifting {
+                            *self.vx_mut(d)? = self.vy(d)?;
                         }
-                        let shifted_bit = (self.vx(inst)? & LEFTMOST_BIT) >> 7;
-                        *self.vx_mut(inst)? <<= 1;
+                        let shifted_bit = (self.vx(d)? & LEFTMOST_BIT) >> 7;
+                        *self.vx_mut(d)? <<= 1;
                         *self.vf_mut()? = shifted_bit;
                     }
                     _ => {
-                        Self::unknown(inst);
+                        self.unknown(d.inst)?;
                     }
                 }
             }
             // conditional skip when vx not equal vy
             0x9 => {
-                if take_n(inst) == 0 {
-                    if self.vx(inst)? != self.vy(inst)? {
-                        self.skip_inst();
+                if d.n == 0 {
+                    if self.vx(d)? != self.vy(d)? {
+                        self.skip_inst()?;
                     }
                 } else {
-                    Self::unknown(inst);
+                    self.unknown(d.inst)?;
                 }
             }
             // set index register
             0xa => {
-                self.ireg = take_nnn(inst);
+                self.ireg = d.nnn;
             }
             // jump with offset
             0xb => {
-                let offset = if BXNN_JUMP_WITH_OFFSET {
-                    self.vx(inst)?
+                let offset = if self.quirks.bxnn_jump_with_offset {
+                    self.vx(d)?
                 } else {
-                    self.vreg.get(0).copied().ok_or(EE::VRegOutOfBounds)?
+                    self.reg(0)?
                 };
-                let jump_to = take_nnn(inst).overflowing_add(offset as u16).0;
+                let jump_to = d.nnn.overflowing_add(offset as u16).0;
                 self.pc = jump_to;
             }
             // random
             0xc => {
-                *self.vx_mut(inst)? = rand::random::<u8>() & take_nn(inst);
+                *self.vx_mut(d)? = self.rng.gen::<u8>() & d.nn;
             }
             // draw
             0xd => {
-                let height = take_n(inst);
-                self.draw_sprite(self.vx(inst)?, self.vy(inst)?, height)?;
+                let height = d.n;
+                self.draw_sprite(self.vx(d)?, self.vy(d)?, height)?;
             }
             // skip if key down
-            0xe => match take_nn(inst) {
+            0xe => match d.nn {
                 0x9e => {
-                    if self.is_key_pressed(self.vx(inst)?.into())? {
-                        self.skip_inst()
+                    if self.is_key_pressed(self.vx(d)?.into())? {
+                        self.skip_inst()?
                     }
                 }
                 0xa1 => {
-                    if !self.is_key_pressed(self.vx(inst)?.into())? {
-                        self.skip_inst()
+                    if !self.is_key_pressed(self.vx(d)?.into())? {
+                        self.skip_inst()?
                     }
                 }
-                _ => Self::unknown(inst),
+                _ => self.unknown(d.inst)?,
             },
             // manipulate timers
             0xf => {
-                match take_nn(inst) {
+                match d.nn {
                     // set vx to delay timer
                     0x07 => {
-                        *self.vx_mut(inst)? = self.delay_timer.load(Ordering::SeqCst);
+                        *self.vx_mut(d)? = self.delay_timer.load(Ordering::SeqCst);
                     }
                     // set delay timer to vx
                     0x15 => {
-                        self.delay_timer.store(self.vx(inst)?, Ordering::SeqCst);
+                        self.delay_timer.store(self.vx(d)?, Ordering::SeqCst);
                     }
                     // set sound timer to vx
                     0x18 => {
-                        self.sound_timer.store(self.vx(inst)?, Ordering::SeqCst);
+                        self.sound_timer.store(self.vx(d)?, Ordering::SeqCst);
                     }
                     // add to index register
                     0x1e => {
-                        let (val, _overflow) = self.ireg.overflowing_add(self.vx(inst)? as u16);
-                        self.ireg = val;
+                        let (val, overflow) = self.ireg.overflowing_add(self.vx(d)? as u16);
+                        // keep index register inside ram so following reads stay valid
+                        self.ireg = (val as usize % RAM_SIZE) as u16;
                         // set vf if index register is outside normal addressing range
-                        if val > 0x0fff {
+                        if overflow || val > 0x0fff {
                             *self.vf_mut()? = 0x1;
                         }
                     }
                     // blocking wait for keypress
                     0x0a => {
-                        let released = self
-                            .released_keys
-                            .iter()
-                            .filter_map(|(k, v)| v.load(Ordering::SeqCst).then(|| *k))
-                            .next();
-
-                        if let Some(rel) = released {
-                            self.released_keys
-                                .get(&rel)
-                                .map(|b| b.store(false, Ordering::SeqCst));
-                            *self.vx_mut(inst)? = rel as u8;
+                        if let Some(rel) = self.take_released_key() {
+                            *self.vx_mut(d)? = rel as u8;
+                            self.consumed_key = Some(rel);
                         } else {
                             self.reverse_inst();
                         }
                     }
                     // set index register to character
                     0x29 => {
-                        let char = self.vx(inst)? & 0x0f;
+                        let char = self.vx(d)? & 0x0f;
                         let char_addr = char as usize * FONT_CHAR_SIZE + FONT_LOAD_ADDR;
                         self.ireg = char_addr as u16;
                     }
                     // binary-coded decimal conversion
                     0x33 => {
-                        let mut vx = self.vx(inst)?;
+                        let mut vx = self.vx(d)?;
                         for dec in (0..3).rev() {
-                            *self
-                                .ram
-                                .get_mut((self.ireg + dec) as usize)
-                                .ok_or(EE::RamError)? = vx % 10;
+                            self.ram_write(self.ireg as usize + dec, vx % 10)?;
                             vx /= 10;
                         }
                     }
                     // store registers in ram
                     0x55 => {
-                        let x = take_x(inst);
+                        let x = d.x;
                         for x_i in 0..=x as usize {
-                            *self
-                                .ram
-                                .get_mut(self.ireg as usize + x_i)
-                                .ok_or(EE::RamError)? =
-                                *self.vreg.get(x_i).ok_or(EE::VRegOutOfBounds)?;
+                            let val = self.reg(x_i)?;
+                            self.ram_write(self.ireg as usize + x_i, val)?;
                         }
-                        if INCREMENT_IREG_ON_REG_TO_MEM {
-                            self.ireg = self.ireg + x as u16 + 1;
+                        if self.quirks.increment_ireg_on_reg_to_mem {
+                            // wrap like FX1E, repeated stores can't overflow index register
+                            self.ireg = self.ireg.wrapping_add(x as u16 + 1) % RAM_SIZE as u16;
                         }
                     }
                     // load registers from ram
                     0x65 => {
-                        let x = take_x(inst);
+                        let x = d.x;
                         for x_i in 0..=x as usize {
-                            *self.vreg.get_mut(x_i).ok_or(EE::VRegOutOfBounds)? =
-                                *self.ram.get(self.ireg as usize + x_i).ok_or(EE::RamError)?;
+                            let val = self.ram_read(self.ireg as usize + x_i)?;
+                            *self.vreg.get_mut(x_i).ok_or(EE::VRegOutOfBounds)? = val;
+                            self.written_vregs |= 1 << x_i;
                         }
-                        if INCREMENT_IREG_ON_REG_TO_MEM {
-                            self.ireg = self.ireg + x as u16 + 1;
+                        if self.quirks.increment_ireg_on_reg_to_mem {
+                            // wrap like FX1E, repeated stores can't overflow index register
+                            self.ireg = self.ireg.wrapping_add(x as u16 + 1) % RAM_SIZE as u16;
                         }
                     }
-                    _ => Self::unknown(inst),
+                    _ => self.unknown(d.inst)?,
                 }
             }
             _ => {
-                Self::unknown(inst);
+                self.unknown(d.inst)?;
             }
         }
         Ok(())
     }
 
-    /// skip one instruction
-    fn skip_inst(&mut self) {
-        self.pc += 2;
+    /// screen and subroutine control, `0NNN` group
+    fn exec_0(&mut self, d: Decoded) -> Result<(), ExecError> {
+        match d.nnn {
+            // clear screen
+            0x0E0 => self.clear_display(),
+            // return from subroutine
+            0x0EE => self.pc = self.stack_pop()?,
+            // superchip scroll, exit and resolution control are not supported
+            0x0C0..=0x0CF | 0x0FB..=0x0FF => self.unknown(d.inst)?,
+            // display readback extension
+            0x0B0..=0x0BF if self.extensions => self.read_display(d.n)?,
+            // machine code routine call, ignored by modern interpreters
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// skip one instruction, fails like `fetch` past top of address space
+    fn skip_inst(&mut self) -> Result<(), ExecError> {
+        self.pc = self.pc.checked_add(2).ok_or(EE::MemoryError)?;
+        Ok(())
     }
 
     /// reverse one instruction, opposite of `skip_inst`
@@ -391,11 +951,78 @@ impl Chip8 {
         self.pc -= 2;
     }
 
+    /// key down state for `EX9E`/`EXA1`, key that satisfied `FX0A` reads as up
+    /// until it is seen up once, so held key doesn't also trigger following skip
     fn is_key_pressed(&self, k: Chip8Key) -> Result<bool, ExecError> {
-        self.down_keys
+        let down = self
+            .down_keys
             .get(&k)
             .map(|ab| ab.load(Ordering::SeqCst))
-            .ok_or(EE::KeymapError)
+            .ok_or(EE::KeymapError)?;
+        Ok(down && self.consumed_key != Some(k))
+    }
+
+    /// whether a 60 Hz frame of clock time passed since ram cheats were last applied
+    fn cheat_frame_due(&mut self) -> bool {
+        let frame_time = Duration::from_secs(1) / TIMERS_FREQ as u32;
+        let now = self.clock.now();
+        let due = now.saturating_sub(self.last_cheat_frame) >= frame_time;
+        if due {
+            self.last_cheat_frame = now;
+        }
+        due
+    }
+
+    /// force cheat values, registers after every instruction and ram on `new_frame`
+    fn apply_cheats(&mut self, new_frame: bool) -> Result<(), ExecError> {
+        let mut result = Ok(());
+        if let Some(cheats) = self.cheats.take() {
+            for &(x, val) in cheats.regs.iter() {
+                self.vreg[x] = val;
+                self.written_vregs |= 1 << x;
+            }
+            if new_frame {
+                result = cheats
+                    .ram
+                    .iter()
+                    .try_for_each(|&(addr, val)| self.ram_write(addr, val));
+            }
+            self.cheats = Some(cheats);
+        }
+        result
+    }
+
+    /// forget key consumed by `FX0A` once it is up, next press is reported normally
+    fn update_consumed_key(&mut self) {
+        if let Some(k) = self.consumed_key {
+            if !self.down_keys[&k].load(Ordering::SeqCst) {
+                self.consumed_key = None;
+            }
+        }
+    }
+
+    /// consume key release for `FX0A`, lowest key wins if several were released at once,
+    /// the others are kept for the following waits if `queue_key_releases` is set
+    fn take_released_key(&mut self) -> Option<Chip8Key> {
+        if self.queue_key_releases {
+            let mut released = self
+                .released_keys
+                .iter()
+                .filter(|(_, v)| v.swap(false, Ordering::SeqCst))
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>();
+            released.sort();
+            self.queued_releases.extend(released);
+            return self.queued_releases.pop_front();
+        }
+        let lowest = self
+            .released_keys
+            .iter()
+            .filter(|(_, v)| v.load(Ordering::SeqCst))
+            .map(|(k, _)| *k)
+            .min()?;
+        self.released_keys[&lowest].store(false, Ordering::SeqCst);
+        Some(lowest)
     }
 
     fn was_key_released(&self, k: Chip8Key) -> Result<bool, ExecError> {
@@ -405,9 +1032,28 @@ impl Chip8 {
             .ok_or(EE::KeymapError)
     }
 
-    /// report unknown instruction encounter
-    fn unknown(inst: u16) {
-        println!("Unknown instruction: {:#06x}", inst);
+    /// handle unknown instruction encounter according to strictness
+    fn unknown(&mut self, inst: u16) -> Result<(), ExecError> {
+        if self.strictness == Strictness::Warn && self.warn_once {
+            // report every distinct opcode once, repeats are only counted
+            if !self.warned_opcodes.insert(inst) {
+                self.repeated_warnings += 1;
+                return Ok(());
+            }
+        }
+        self.tolerate(EE::UnknownInstruction(inst))
+    }
+
+    /// continue after recoverable `err` unless strict, log it in warn mode
+    fn tolerate(&self, err: ExecError) -> Result<(), ExecError> {
+        match self.strictness {
+            Strictness::Lenient => Ok(()),
+            Strictness::Warn => {
+                println!("{} at {:#06x}", err, self.inst_addr);
+                Ok(())
+            }
+            Strictness::Strict => Err(err),
+        }
     }
 }
 
@@ -424,39 +1070,84 @@ impl Chip8 {
         Ok(())
     }
 
+    /// byte at `addr`, served by memory mapped io handler if address is in its range
+    fn peek(&self, addr: usize) -> Option<u8> {
+        self.ram_accesses.set(self.ram_accesses.get() + 1);
+        match &self.mmio {
+            Some((range, handler)) if range.contains(&addr) => Some(handler(addr)),
+            _ => self.ram.get(addr).copied(),
+        }
+    }
+
+    /// read ram byte, out of range reads give zero unless strict
+    fn ram_read(&self, addr: usize) -> Result<u8, ExecError> {
+        match self.peek(addr) {
+            Some(val) => Ok(val),
+            None => self.tolerate(EE::MemoryError).map(|_| 0),
+        }
+    }
+
+    /// write ram byte, out of range writes are dropped unless strict
+    fn ram_write(&mut self, addr: usize, val: u8) -> Result<(), ExecError> {
+        self.ram_accesses.set(self.ram_accesses.get() + 1);
+        if self.warn_font_writes && (FONT_LOAD_ADDR..FONT_LOAD_ADDR + FONT_SIZE).contains(&addr) {
+            println!(
+                "Font overwritten at {:#06x} by instruction at {:#06x}",
+                addr, self.inst_addr
+            );
+        }
+        let code_region = self.inst_addr as usize..ROM_LOAD_ADDR + self.rom_size;
+        if self.warn_self_modify && code_region.contains(&addr) {
+            println!(
+                "Code at {:#06x} modified by instruction at {:#06x}",
+                addr, self.inst_addr
+            );
+        }
+        if let Some(cache) = self.predecoded.as_mut() {
+            // both instructions containing this byte are stale
+            for stale in [addr.wrapping_sub(1), addr] {
+                if let Some(entry) = cache.get_mut(stale) {
+                    *entry = None;
+                }
+            }
+        }
+        match self.ram.get_mut(addr) {
+            Some(byte) => {
+                *byte = val;
+                Ok(())
+            }
+            None => self.tolerate(EE::RamError),
+        }
+    }
+
     fn stack_push(&mut self, val: u16) -> Result<(), ExecError> {
-        *self
-            .stack
-            .get_mut((self.sp + 1) as usize)
-            .ok_or(EE::StackOverflow)? = val;
+        *self.stack.get_mut(self.sp).ok_or(EE::StackOverflow)? = val;
         self.sp += 1;
         Ok(())
     }
 
     fn stack_pop(&mut self) -> Result<u16, ExecError> {
-        if self.sp >= 0 {
-            let val = *self.stack.get(self.sp as usize).ok_or(EE::StackOverflow)?;
-            self.sp -= 1;
-            Ok(val)
-        } else {
-            Err(EE::StackUnderflow)
+        if self.sp == 0 {
+            return Err(EE::StackUnderflow);
         }
+        self.sp -= 1;
+        self.stack.get(self.sp).copied().ok_or(EE::StackOverflow)
+    }
+
+    /// value of register `x`, read goes through uninitialized read check
+    fn reg(&self, x: usize) -> Result<u8, ExecError> {
+        self.check_written(x);
+        self.vreg.get(x).copied().ok_or(EE::VRegOutOfBounds)
     }
 
     /// shortcut for taking vx value
-    fn vx(&self, inst: u16) -> Result<u8, ExecError> {
-        self.vreg
-            .get(take_x(inst) as usize)
-            .copied()
-            .ok_or(EE::VRegOutOfBounds)
+    fn vx(&self, d: Decoded) -> Result<u8, ExecError> {
+        self.reg(d.x as usize)
     }
 
     /// shortcut for taking vy value
-    fn vy(&self, inst: u16) -> Result<u8, ExecError> {
-        self.vreg
-            .get(take_y(inst) as usize)
-            .copied()
-            .ok_or(EE::VRegOutOfBounds)
+    fn vy(&self, d: Decoded) -> Result<u8, ExecError> {
+        self.reg(d.y as usize)
     }
 
     // shortcut for taking vf value
@@ -468,23 +1159,29 @@ impl Chip8 {
     }
 
     /// shortcut for taking vx mutable reference
-    fn vx_mut(&mut self, inst: u16) -> Result<&mut u8, ExecError> {
-        self.vreg
-            .get_mut(take_x(inst) as usize)
-            .ok_or(EE::VRegOutOfBounds)
+    fn vx_mut(&mut self, d: Decoded) -> Result<&mut u8, ExecError> {
+        self.written_vregs |= 1 << d.x;
+        self.vreg.get_mut(d.x as usize).ok_or(EE::VRegOutOfBounds)
     }
 
     /// shortcut for taking vy mutable reference
-    fn vy_mut(&mut self, inst: u16) -> Result<&mut u8, ExecError> {
-        self.vreg
-            .get_mut(take_y(inst) as usize)
-            .ok_or(EE::VRegOutOfBounds)
+    fn vy_mut(&mut self, d: Decoded) -> Result<&mut u8, ExecError> {
+        self.written_vregs |= 1 << d.y;
+        self.vreg.get_mut(d.y as usize).ok_or(EE::VRegOutOfBounds)
     }
 
     /// shortcut for taking vf mutable reference
     fn vf_mut(&mut self) -> Result<&mut u8, ExecError> {
+        self.written_vregs |= 1 << VF_REG_FLAG;
         self.vreg.get_mut(VF_REG_FLAG).ok_or(EE::VRegOutOfBounds)
     }
+
+    /// warn if register is read before rom wrote to it, when enabled
+    fn check_written(&self, reg: usize) {
+        if self.warn_uninit_reads && self.written_vregs & (1 << reg) == 0 {
+            println!("V{:X} read before written at {:#06x}", reg, self.inst_addr);
+        }
+    }
 }
 
 // value for pixel being on, i.e. white
@@ -496,11 +1193,68 @@ pub const PIXEL_OFF: u8 = 0x00;
 // VF register address which is treated as flag
 const VF_REG_FLAG: usize = 0x0f;
 const LEFTMOST_BIT: u8 = 0b1000_0000;
+// frames without clear after which back buffer is shown anyway
+const PRESENT_FALLBACK_FRAMES: u32 = 2;
 
-// translate XY location to 1-dim array index
-#[inline]
-pub const fn loc_to_idx(x: usize, y: usize) -> usize {
-    y * DISPLAY_W + x
+/// display buffer together with its dimensions
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Display {
+    pub w: usize,
+    pub h: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Display {
+    pub fn new(w: usize, h: usize) -> Self {
+        Self {
+            w,
+            h,
+            pixels: vec![PIXEL_OFF; w * h],
+        }
+    }
+
+    // translate XY location to 1-dim array index
+    #[inline]
+    pub const fn loc_to_idx(&self, x: usize, y: usize) -> usize {
+        y * self.w + x
+    }
+
+    /// pixel at XY location, `None` if outside display
+    pub fn get(&self, x: usize, y: usize) -> Option<u8> {
+        (x < self.w && y < self.h).then(|| self.pixels[self.loc_to_idx(x, y)])
+    }
+
+    /// mutable pixel at XY location, `None` if outside display
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut u8> {
+        let idx = self.loc_to_idx(x, y);
+        (x < self.w && y < self.h).then(|| &mut self.pixels[idx])
+    }
+
+    /// flip state of pixel, doesn't wrap around,
+    /// return `true` if pixel was turned off
+    pub fn flip(&mut self, x: usize, y: usize) -> bool {
+        self.get_mut(x, y)
+            .map(|p| {
+                let was_on = *p == PIXEL_ON;
+                if was_on {
+                    *p = PIXEL_PRE_OFF;
+                } else {
+                    *p = PIXEL_ON;
+                }
+                was_on
+            })
+            .unwrap_or(false)
+    }
+
+    /// one line per row, `#` for lit pixels and `.` for the rest
+    pub fn to_text(&self) -> String {
+        let mut text = String::with_capacity((self.w + 1) * self.h);
+        for row in self.pixels.chunks(self.w) {
+            text.extend(row.iter().map(|&p| if is_pixel_on(p) { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    }
 }
 
 #[inline]
@@ -510,36 +1264,94 @@ pub const fn is_pixel_on(pixel: u8) -> bool {
 
 /// display management
 impl Chip8 {
+    /// change display resolution, contents are cleared
+    pub fn set_display_size(&mut self, w: usize, h: usize) {
+        *self.display.lock().unwrap() = Display::new(w, h);
+        if self.back_buffer.is_some() {
+            self.back_buffer = Some(Display::new(w, h));
+        }
+    }
+
+    /// cache decoded instructions by address so hot loops skip fetch and decode,
+    /// ram must then be written only through instructions, cheats or `load_state`
+    pub fn set_predecode(&mut self, enabled: bool) {
+        self.predecoded = enabled.then(|| vec![None; RAM_SIZE]);
+    }
+
+    /// draw into back buffer which is shown only on clear or when rom doesn't clear for a while
+    pub fn set_buffered_display(&mut self, enabled: bool) {
+        self.back_buffer = enabled.then(|| self.display.lock().unwrap().clone());
+    }
+
     fn clear_display(&mut self) {
-        self.display.lock().unwrap().iter_mut().for_each(|pixel| {
-            if *pixel > PIXEL_PRE_OFF {
-                *pixel = PIXEL_PRE_OFF
+        if self.back_buffer.is_some() {
+            // show finished frame, next one is drawn from blank
+            self.present();
+            if let Some(back) = self.back_buffer.as_mut() {
+                back.pixels.fill(PIXEL_OFF);
+            }
+            return;
+        }
+        self.display
+            .lock()
+            .unwrap()
+            .pixels
+            .iter_mut()
+            .for_each(|pixel| {
+                if *pixel > PIXEL_PRE_OFF {
+                    *pixel = PIXEL_PRE_OFF
+                }
+            });
+    }
+
+    /// copy back buffer to shown display, pixels turned off since last time start fading
+    fn present(&mut self) {
+        if let Some(back) = self.back_buffer.as_ref() {
+            let mut display = self.display.lock().unwrap();
+            for (shown, &drawn) in display.pixels.iter_mut().zip(back.pixels.iter()) {
+                if is_pixel_on(drawn) {
+                    *shown = PIXEL_ON;
+                } else if is_pixel_on(*shown) {
+                    *shown = PIXEL_PRE_OFF;
+                }
             }
-        });
+        }
+        self.last_present = self.clock.now();
+    }
+
+    /// show back buffer of rom that doesn't clear screen, only after a few frames
+    /// to avoid showing partially drawn frames of roms that do
+    fn present_if_stale(&mut self) {
+        let stale_after = Duration::from_secs(1) * PRESENT_FALLBACK_FRAMES / TIMERS_FREQ as u32;
+        if self.back_buffer.is_some()
+            && self.clock.now().saturating_sub(self.last_present) >= stale_after
+        {
+            self.present();
+        }
     }
 
     fn get_pixel_value(&mut self, x: usize, y: usize) -> u8 {
-        *self
-            .display
-            .lock()
-            .unwrap()
-            .get(loc_to_idx(x, y))
-            .unwrap_or_else(|| &PIXEL_OFF)
+        self.display.lock().unwrap().get(x, y).unwrap_or(PIXEL_OFF)
     }
 
     fn draw_sprite(&mut self, x: u8, y: u8, h: u8) -> Result<(), ExecError> {
         *self.vf_mut()? = 0x00;
         // position sprite inside display
-        let x = x as usize % DISPLAY_W;
-        let y = y as usize % DISPLAY_H;
+        let (display_w, display_h) = {
+            let display = self.display.lock().unwrap();
+            (display.w, display.h)
+        };
+        let x = x as usize % display_w;
+        let y = y as usize % display_h;
         // sprite is located at `ireg` memory address
         for line_i in 0..h as usize {
-            let addr = self.ireg + line_i as u16;
-            let line = *self.ram.get(addr as usize).ok_or(EE::MemoryError)?;
+            let addr = self.ireg.wrapping_add(line_i as u16);
+            let line = self.ram_read(addr as usize)?;
             for bit_i in 0..8usize {
                 if (LEFTMOST_BIT >> bit_i) & line != 0 {
                     if self.flip_pixel(x + bit_i, y + line_i) {
                         *self.vf_mut()? = 0x01;
+                        self.collision.store(true, Ordering::Relaxed);
                     }
                 }
             }
@@ -547,40 +1359,66 @@ impl Chip8 {
         Ok(())
     }
 
-    /// flip state of pixel on screen, doesn't wrap around,
+    /// `00BN` extension, inverse of `DXYN`: store `n` rows of 8 pixels at (v0, v1) into ram
+    /// at `ireg`, one byte per row with leftmost pixel in highest bit, lit pixels are ones
+    fn read_display(&mut self, h: u8) -> Result<(), ExecError> {
+        let display = match self.back_buffer.as_ref() {
+            Some(back) => back.clone(),
+            None => self.display.lock().unwrap().clone(),
+        };
+        let x = self.reg(0)? as usize % display.w;
+        let y = self.reg(1)? as usize % display.h;
+        for line_i in 0..h as usize {
+            let mut line = 0;
+            for bit_i in 0..8usize {
+                if display.get(x + bit_i, y + line_i).is_some_and(is_pixel_on) {
+                    line |= LEFTMOST_BIT >> bit_i;
+                }
+            }
+            self.ram_write(self.ireg as usize + line_i, line)?;
+        }
+        Ok(())
+    }
+
+    /// flip state of pixel in back buffer if used or on screen otherwise,
     /// return `true` if pixel was turned off
     fn flip_pixel(&mut self, x: usize, y: usize) -> bool {
-        self.display
-            .lock()
-            .unwrap()
-            .get_mut(loc_to_idx(x, y))
-            .map(|p| {
-                let was_on = *p == PIXEL_ON;
-                if was_on {
-                    *p = PIXEL_PRE_OFF;
-                } else {
-                    *p = PIXEL_ON;
-                }
-                was_on
-            })
-            .unwrap_or_else(|| false)
+        match self.back_buffer.as_mut() {
+            Some(back) => back.flip(x, y),
+            None => self.display.lock().unwrap().flip(x, y),
+        }
     }
 }
 
-pub fn decrement_timers_routine(timers: Vec<Arc<AtomicU8>>) {
+pub fn decrement_timers_routine(
+    timers: Vec<Arc<AtomicU8>>,
+    shutdown: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>,
+) {
     let time_per_cycle = Duration::from_secs(1) / TIMERS_FREQ as u32;
-    loop {
-        let clock = Instant::now();
-        // check and decrement timers
+    let tick_of = |time: Duration| (time.as_nanos() / time_per_cycle.as_nanos()) as u64;
+    let mut last_tick = tick_of(clock.now());
+    while !shutdown.load(Ordering::SeqCst) {
+        // decrement once per tick passed, several times if thread was stalled
+        let tick = tick_of(clock.now());
+        if paused.load(Ordering::SeqCst) {
+            // ticks passed while paused don't count
+            last_tick = tick;
+            clock.sleep(PAUSE_POLL_INTERVAL);
+            continue;
+        }
+        let passed = (tick - last_tick).min(u8::MAX as u64) as u8;
+        last_tick = tick;
         for timer in timers.iter() {
             let mut old_t = timer.load(Ordering::Relaxed);
             loop {
-                if old_t == 0 {
+                if old_t == 0 || passed == 0 {
                     break;
                 }
                 match timer.compare_exchange_weak(
                     old_t,
-                    old_t - 1,
+                    old_t.saturating_sub(passed),
                     Ordering::SeqCst,
                     Ordering::Relaxed,
                 ) {
@@ -589,12 +1427,271 @@ pub fn decrement_timers_routine(timers: Vec<Arc<AtomicU8>>) {
                 }
             }
         }
-        // wait to meet timing
-        let inst_time = clock.elapsed();
-        if let Some(sleep_time) = time_per_cycle.checked_sub(inst_time) {
-            thread::sleep(sleep_time);
-        } else {
-            println!("Decrementing timers took longer than expected");
+        // wait for next tick
+        let next_tick = Duration::from_nanos(time_per_cycle.as_nanos() as u64 * (tick + 1));
+        clock.sleep(next_tick.saturating_sub(clock.now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::ManualClock;
+
+    fn device(rom: &[u8]) -> Chip8 {
+        Chip8::from_bytes(rom.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn stack_holds_exactly_stack_size_frames() {
+        let mut device = device(&[]);
+        for i in 0..STACK_SIZE {
+            device.stack_push(i as u16).unwrap();
+        }
+        assert_eq!(device.stack_push(0), Err(EE::StackOverflow));
+        for i in (0..STACK_SIZE).rev() {
+            assert_eq!(device.stack_pop(), Ok(i as u16));
+        }
+        assert_eq!(device.stack_pop(), Err(EE::StackUnderflow));
+    }
+
+    #[test]
+    fn add_to_index_wraps_into_ram_and_sets_vf() {
+        let mut device = device(&[]);
+        device.ireg = 0x0ff0;
+        device.vreg[0] = 0xff;
+        device.decode_and_execute(0xf01e).unwrap();
+        assert_eq!(device.ireg, 0x00ef);
+        assert_eq!(device.vreg[VF_REG_FLAG], 1);
+    }
+
+    fn release(device: &Chip8, k: Chip8Key) {
+        device.released_keys[&k].store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn key_wait_takes_lowest_of_keys_released_together() {
+        let mut device = device(&[0xf0, 0x0a]);
+        release(&device, Chip8Key::B);
+        release(&device, Chip8Key::K3);
+        device.step().unwrap();
+        assert_eq!(device.vreg[0], 0x3);
+        assert_eq!(device.pc, 0x202);
+    }
+
+    #[test]
+    fn key_wait_gets_queued_release() {
+        let mut device = device(&[0xf0, 0x0a, 0xf1, 0x0a]);
+        device.queue_key_releases = true;
+        release(&device, Chip8Key::B);
+        release(&device, Chip8Key::K3);
+        device.step().unwrap();
+        device.step().unwrap();
+        assert_eq!(device.vreg[0], 0x3);
+        assert_eq!(device.vreg[1], 0xb);
+        assert_eq!(device.pc, 0x204);
+    }
+
+    /// manual clock shutting timers routine down once it passes `stop_at`,
+    /// first sleep lasts `stall` instead of requested time if set
+    struct StoppingClock {
+        clock: ManualClock,
+        stop_at: Duration,
+        stall: Mutex<Option<Duration>>,
+        shutdown: Arc<AtomicBool>,
+    }
+
+    impl Clock for StoppingClock {
+        fn now(&self) -> Duration {
+            self.clock.now()
         }
+
+        fn sleep(&self, time: Duration) {
+            let stall = self.stall.lock().unwrap().take();
+            self.clock.sleep(stall.unwrap_or(time));
+            if self.clock.now() >= self.stop_at {
+                self.shutdown.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// let timers routine run for exactly `ticks` 60 Hz ticks of manual time,
+    /// with `stalled` all of them pass during its first sleep
+    fn run_timers(device: &Chip8, ticks: u32, stalled: bool) {
+        let per_tick = Duration::from_secs(1) / TIMERS_FREQ as u32;
+        let clock = StoppingClock {
+            clock: ManualClock::new(),
+            stop_at: per_tick * ticks + per_tick / 2,
+            stall: Mutex::new(stalled.then_some(per_tick * ticks)),
+            shutdown: Arc::clone(&device.shutdown),
+        };
+        decrement_timers_routine(
+            vec![
+                Arc::clone(&device.delay_timer),
+                Arc::clone(&device.sound_timer),
+            ],
+            Arc::clone(&device.shutdown),
+            Arc::clone(&device.paused),
+            Arc::new(clock),
+        );
+        device.shutdown.store(false, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn delay_timer_read_back_after_ticks() {
+        // v0 = 5, delay = v0, v1 = delay
+        let mut device = device(&[0x60, 0x05, 0xf0, 0x15, 0xf1, 0x07]);
+        device.step().unwrap();
+        device.step().unwrap();
+        run_timers(&device, 3, false);
+        device.step().unwrap();
+        assert_eq!(device.vreg[1], 2);
+    }
+
+    #[test]
+    fn bcd_writes_hundreds_tens_units() {
+        for (vx, digits) in [(0, [0, 0, 0]), (128, [1, 2, 8]), (255, [2, 5, 5])] {
+            let mut device = device(&[]);
+            device.ireg = 0x300;
+            device.vreg[0] = vx;
+            device.decode_and_execute(0xf033).unwrap();
+            assert_eq!(device.ram[0x300..0x303], digits, "vx = {}", vx);
+        }
+    }
+
+    #[test]
+    fn key_satisfying_wait_doesnt_trigger_following_skip() {
+        // wait for key into v0, skip if v0 down, clear, skip if v0 down
+        let mut device = device(&[0xf0, 0x0a, 0xe0, 0x9e, 0x00, 0xe0, 0xe0, 0x9e]);
+        let down = Arc::clone(&device.down_keys[&Chip8Key::K5]);
+        down.store(true, Ordering::SeqCst);
+        release(&device, Chip8Key::K5);
+        device.step().unwrap();
+        device.update_consumed_key();
+        assert_eq!(device.vreg[0], 0x5);
+        // still held from the wait
+        device.step().unwrap();
+        assert_eq!(device.pc, 0x204);
+        // pressed anew after being seen up
+        down.store(false, Ordering::SeqCst);
+        device.update_consumed_key();
+        down.store(true, Ordering::SeqCst);
+        device.step().unwrap();
+        device.step().unwrap();
+        assert_eq!(device.pc, 0x20a);
+    }
+
+    #[test]
+    fn timers_catch_up_after_stall() {
+        let device = device(&[]);
+        device.delay_timer.store(10, Ordering::SeqCst);
+        device.sound_timer.store(3, Ordering::SeqCst);
+        run_timers(&device, 5, true);
+        assert_eq!(device.delay_timer.load(Ordering::SeqCst), 5);
+        assert_eq!(device.sound_timer.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn state_reports_pause() {
+        let device = device(&[]);
+        device.paused.store(true, Ordering::SeqCst);
+        assert_eq!(device.state(), MachineState::Paused);
+        device.paused.store(false, Ordering::SeqCst);
+        assert_eq!(device.state(), MachineState::Running);
+    }
+
+    #[test]
+    fn fetch_at_top_of_memory_fails_cleanly() {
+        let mut device = device(&[]);
+        for pc in [0x0fff, 0xffff] {
+            device.pc = pc;
+            assert_eq!(device.fetch(), Err(EE::MemoryError));
+        }
+        // mapped addresses are readable, but pc can't move past them
+        device.mmio = Some((0xf000..0x10000, Box::new(|_| 0)));
+        device.pc = 0xfffe;
+        assert_eq!(device.fetch(), Err(EE::MemoryError));
+    }
+
+    #[test]
+    fn key_skip_with_register_above_0xf() {
+        let mut device = device(&[0x60, 0xff, 0xe0, 0x9e]);
+        device.step().unwrap();
+        assert_eq!(device.step(), Ok(0xe09e));
+    }
+
+    #[test]
+    fn headless_run_calls_cycle_hook() {
+        let mut device = device(&[0x12, 0x00]);
+        let cycles = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&cycles);
+        device.on_cycle = Some(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        device.run_headless(5).unwrap();
+        assert_eq!(cycles.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn predecode_cache_dropped_on_write() {
+        let mut device = device(&[0x60, 0x05]);
+        device.set_predecode(true);
+        device.step().unwrap();
+        // direct ram edits bypass invalidation, cached instruction runs again
+        device.ram[0x201] = 0x06;
+        device.pc = 0x200;
+        device.step().unwrap();
+        assert_eq!(device.vreg[0], 0x05);
+        // writes from instructions drop it
+        device.ram_write(0x201, 0x07).unwrap();
+        device.pc = 0x200;
+        device.step().unwrap();
+        assert_eq!(device.vreg[0], 0x07);
+    }
+
+    #[test]
+    fn headless_ram_cheats_follow_timer_ticks() {
+        let ticks_every = IPS.get() as usize / TIMERS_FREQ;
+        for (instructions, applied) in [(ticks_every - 1, 0), (ticks_every, 7)] {
+            let mut device = device(&[0x12, 0x00]);
+            device.cheats = Some(Cheats {
+                ram: vec![(0x300, 7)],
+                regs: Vec::new(),
+            });
+            device.run_headless(instructions).unwrap();
+            assert_eq!(device.ram[0x300], applied);
+        }
+    }
+
+    #[test]
+    fn store_and_load_increment_wraps_index_register() {
+        for inst in [0xf355, 0xf365] {
+            let mut device = device(&[]);
+            device.quirks = Quirks::cosmac();
+            device.strictness = Strictness::Lenient;
+            device.ireg = 0xfffe;
+            device.decode_and_execute(inst).unwrap();
+            assert_eq!(device.ireg, 0x0002);
+        }
+    }
+
+    #[test]
+    fn sprite_at_top_of_index_range_doesnt_overflow() {
+        let mut device = device(&[]);
+        device.strictness = Strictness::Lenient;
+        device.ireg = 0xffff;
+        device.decode_and_execute(0xd002).unwrap();
+    }
+
+    #[test]
+    fn skip_at_top_of_memory_fails_cleanly() {
+        let mut device = device(&[]);
+        // skip if v0 == 0 mapped at top of address space
+        device.mmio = Some((
+            0xf000..0x10000,
+            Box::new(|addr| if addr % 2 == 0 { 0x30 } else { 0x00 }),
+        ));
+        device.pc = 0xfffc;
+        assert_eq!(device.step(), Err(EE::MemoryError));
     }
 }
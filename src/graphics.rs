@@ -1,66 +1,262 @@
 use crate::{
-    device::{loc_to_idx, DISPLAY_H, DISPLAY_SIZE, DISPLAY_W, PIXEL_OFF, PIXEL_ON},
-    util::Chip8Key,
+    device::{
+        Display, MachineState, FONT_LOAD_ADDR, PIXEL_OFF, PIXEL_ON, PIXEL_PRE_OFF, RAM_SIZE,
+        ROM_LOAD_ADDR, TIMERS_FREQ,
+    },
+    timing::TimeHistogram,
+    util::{Chip8Key, Radix, FONT_SIZE},
 };
-use macroquad::prelude::*;
+use macroquad::{color::hsl_to_rgb, prelude::*};
 use std::{
     collections::HashMap,
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+        Arc, Mutex, TryLockError,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 // amount of space between display pixels in %
 const BORDER_OFFSET_PERCENT: u8 = 5;
 // speed of pixel dimming effect per frame, full white is 255
 const FADE_AMOUNT: u8 = 30;
+// thickness of screen border flashed on sprite collision
+const COLLISION_FLASH_WIDTH: f32 = 8.0;
+// key toggling memory map overlay
+const MEMORY_MAP_KEY: KeyCode = KeyCode::M;
+// key toggling pause
+const PAUSE_KEY: KeyCode = KeyCode::P;
+// key executing next instruction in step mode
+const STEP_KEY: KeyCode = KeyCode::N;
+// key restarting rom with ram kept
+const WARM_RESET_KEY: KeyCode = KeyCode::F5;
+// key held to run faster
+const TURBO_KEY: KeyCode = KeyCode::Tab;
+// keys saving and restoring emulator state
+const QUICKSAVE_KEY: KeyCode = KeyCode::F6;
+const QUICKLOAD_KEY: KeyCode = KeyCode::F9;
+// height of memory map bar in screen pixels
+const MEMORY_MAP_HEIGHT: f32 = 12.0;
+
+/// ram layout and positions shown by memory map overlay
+pub struct MemoryMap {
+    pub pc: Arc<AtomicU16>,
+    pub ireg: Arc<AtomicU16>,
+    pub rom_size: usize,
+    /// number base of shown addresses
+    pub radix: Radix,
+}
+
+/// device state shared with render loop
+pub struct SharedDevice {
+    pub display: Arc<Mutex<Display>>,
+    pub down_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
+    pub released_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
+    pub keymap: HashMap<Chip8Key, KeyCode>,
+    pub shutdown: Arc<AtomicBool>,
+    pub paused: Arc<AtomicBool>,
+    pub step: Arc<AtomicUsize>,
+    pub reset: Arc<AtomicBool>,
+    pub turbo: Arc<AtomicBool>,
+    pub save: Arc<AtomicBool>,
+    pub load: Arc<AtomicBool>,
+    pub state: Arc<Mutex<MachineState>>,
+    pub collision: Arc<AtomicBool>,
+    pub memory_map: MemoryMap,
+}
+
+/// coloring of pixels which are being turned off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeStyle {
+    /// dim towards black
+    Dim,
+    /// dim while walking along hue wheel, wheel rotates with time
+    HueCycle,
+}
+
+impl FromStr for FadeStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dim" => Ok(Self::Dim),
+            "hue" => Ok(Self::HueCycle),
+            _ => Err(format!("Unknown fade style: {}", s)),
+        }
+    }
+}
+
+/// presentation settings, these don't affect emulation
+pub struct RenderConfig {
+    /// opacity of display pixels, allows compositing with overlays
+    pub alpha: u8,
+    /// coloring of fading pixels
+    pub fade_style: FadeStyle,
+    /// hue wheel revolutions per second for `FadeStyle::HueCycle`
+    pub hue_speed: f32,
+    /// how long to wait for display lock before redrawing previous frame
+    pub lock_timeout: Duration,
+    /// upper limit of frames per second, `None` renders as fast as vsync allows
+    pub max_fps: Option<u32>,
+    /// fade pixels out within this many 60 Hz frames whatever render rate is,
+    /// `None` fades by fixed amount per rendered frame
+    pub trail_frames: Option<u32>,
+    /// show black frame instead of display every this many frames, experimental
+    pub blank_every: Option<u32>,
+    /// gamma applied to fading pixel brightness, 1.0 keeps it linear
+    pub gamma: f32,
+    /// flash screen border when sprite draw collides, debug aid
+    pub collision_flash: bool,
+    /// scale pixels by whole screen pixels and letterbox instead of stretching
+    pub integer_scale: bool,
+    /// mirror display left to right
+    pub flip_h: bool,
+    /// mirror display top to bottom, together with `flip_h` rotates by 180 degrees
+    pub flip_v: bool,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            alpha: u8::MAX,
+            fade_style: FadeStyle::Dim,
+            hue_speed: 0.25,
+            lock_timeout: Duration::from_millis(2),
+            max_fps: None,
+            trail_frames: None,
+            blank_every: None,
+            gamma: 1.0,
+            collision_flash: false,
+            integer_scale: false,
+            flip_h: false,
+            flip_v: false,
+        }
+    }
+}
+
+/// key polling settings, these may affect what emulated program sees
+#[derive(Default)]
+pub struct InputConfig {
+    /// keep reporting key as down for this long after physical release,
+    /// so quick taps aren't missed between frames
+    pub key_hold: Duration,
+}
 
 pub async fn display_draw(
-    display: Arc<Mutex<[u8; DISPLAY_SIZE]>>,
-    down_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
-    released_keys: HashMap<Chip8Key, Arc<AtomicBool>>,
-    keymap: HashMap<Chip8Key, KeyCode>,
+    device: SharedDevice,
+    config: RenderConfig,
+    input: InputConfig,
+    frame_histogram: &mut Option<TimeHistogram>,
 ) {
-    let tiles_w = DISPLAY_W as f32;
-    let tiles_h = DISPLAY_H as f32;
+    let SharedDevice {
+        display,
+        down_keys,
+        released_keys,
+        keymap,
+        shutdown,
+        paused,
+        step,
+        reset,
+        turbo,
+        save,
+        load,
+        state,
+        collision,
+        memory_map,
+    } = device;
     let offset = BORDER_OFFSET_PERCENT as f32 / 100.0;
 
+    // handle window close ourselves to let other threads finish
+    prevent_quit();
+    let mut frame_clock = Instant::now();
+    let mut show_memory_map = false;
+    let mut frame_count: u32 = 0;
+    let mut last_down: HashMap<Chip8Key, Instant> = HashMap::new();
+    let mut display_state = display.lock().unwrap().clone();
+
     loop {
+        if is_quit_requested() {
+            shutdown.store(true, Ordering::SeqCst);
+            break;
+        }
+        let frame_time = frame_clock.elapsed();
+        if let Some(histogram) = frame_histogram.as_mut() {
+            histogram.record(frame_time);
+        }
+        frame_clock = Instant::now();
+
         clear_background(BLACK);
 
+        // reuse previous frame if device holds display for too long
+        let fade_amount = config
+            .trail_frames
+            .map_or(FADE_AMOUNT, |frames| trail_fade_amount(frames, frame_time));
+        if let Some(snapshot) = fade_and_snapshot(&display, fade_amount, config.lock_timeout) {
+            display_state = snapshot;
+        }
+
         let sw = screen_width();
         let sh = screen_height();
-        let tw = sw / tiles_w;
-        let th = sh / tiles_h;
-        let sw_off = tw * offset;
-        let sh_off = th * offset;
-
-        let mut display_handle = display.lock().unwrap();
-        display_handle.iter_mut().for_each(|pixel| {
-            if *pixel < PIXEL_ON && *pixel > PIXEL_OFF {
-                *pixel = pixel.saturating_sub(FADE_AMOUNT);
-            }
-        });
-        let display_state = display_handle.clone();
-        drop(display_handle); // minimize time holding display lock
-        for x_i in 0..DISPLAY_W {
-            for y_i in 0..DISPLAY_H {
-                if let Some(&v) = display_state.get(loc_to_idx(x_i, y_i)) {
-                    draw_rectangle(
-                        x_i as f32 * tw + sw_off,
-                        y_i as f32 * th + sh_off,
-                        tw - sw_off,
-                        th - sh_off,
-                        Color::from_rgba(v, v, v, u8::MAX),
-                    );
+        let (w, h) = (display_state.w as f32, display_state.h as f32);
+        let (tw, th, ox, oy, sw_off, sh_off) = if config.integer_scale {
+            // whole screen pixels per display pixel so all are equally sized, centered
+            let (tw, th) = ((sw / w).floor().max(1.0), (sh / h).floor().max(1.0));
+            let (ox, oy) = (((sw - tw * w) / 2.0).floor(), ((sh - th * h) / 2.0).floor());
+            let (sw_off, sh_off) = ((tw * offset).round(), (th * offset).round());
+            (tw, th, ox, oy, sw_off, sh_off)
+        } else {
+            let (tw, th) = (sw / w, sh / h);
+            (tw, th, 0.0, 0.0, tw * offset, th * offset)
+        };
+
+        // optionally leave occasional frame black to reduce ghosting
+        frame_count = frame_count.wrapping_add(1);
+        let blank = config
+            .blank_every
+            .is_some_and(|n| frame_count.is_multiple_of(n.max(1)));
+
+        // nothing to draw into while window is minimized
+        let minimized = sw < 1.0 || sh < 1.0;
+
+        let time = get_time() as f32;
+        if !blank && !minimized {
+            for x_i in 0..display_state.w {
+                for y_i in 0..display_state.h {
+                    if let Some(v) = display_state.get(x_i, y_i) {
+                        // mirroring only changes where pixel is shown
+                        let sx = if config.flip_h {
+                            display_state.w - 1 - x_i
+                        } else {
+                            x_i
+                        };
+                        let sy = if config.flip_v {
+                            display_state.h - 1 - y_i
+                        } else {
+                            y_i
+                        };
+                        draw_rectangle(
+                            ox + sx as f32 * tw + sw_off,
+                            oy + sy as f32 * th + sh_off,
+                            tw - sw_off,
+                            th - sh_off,
+                            pixel_color(v, &config, time),
+                        );
+                    }
                 }
             }
         }
 
-        for (ref k, ref state) in down_keys.iter() {
-            let code = keymap[k];
-            state.store(is_key_down(code), Ordering::SeqCst);
+        for (k, state) in down_keys.iter() {
+            let down = is_key_down(keymap[k]);
+            if down {
+                last_down.insert(*k, Instant::now());
+            }
+            let held = last_down
+                .get(k)
+                .is_some_and(|t| t.elapsed() < input.key_hold);
+            state.store(down || held, Ordering::SeqCst);
         }
 
         for (ref k, ref state) in released_keys.iter() {
@@ -68,7 +264,170 @@ pub async fn display_draw(
             state.store(is_key_released(code), Ordering::SeqCst);
         }
 
+        if collision.swap(false, Ordering::Relaxed) && config.collision_flash {
+            draw_rectangle_lines(0.0, 0.0, sw, sh, COLLISION_FLASH_WIDTH, RED);
+        }
+
+        if let MachineState::Error(err) = *state.lock().unwrap() {
+            draw_text(&format!("Execution stopped: {}", err), 4.0, 20.0, 24.0, RED);
+        }
+
+        if is_key_pressed(PAUSE_KEY) {
+            paused.fetch_xor(true, Ordering::SeqCst);
+        }
+        if paused.load(Ordering::SeqCst) {
+            draw_text("Paused", 4.0, 44.0, 24.0, YELLOW);
+        }
+
+        if is_key_pressed(STEP_KEY) {
+            step.fetch_add(1, Ordering::SeqCst);
+        }
+
+        turbo.store(is_key_down(TURBO_KEY), Ordering::Relaxed);
+
+        if is_key_pressed(WARM_RESET_KEY) {
+            reset.store(true, Ordering::SeqCst);
+        }
+        if is_key_pressed(QUICKSAVE_KEY) {
+            save.store(true, Ordering::SeqCst);
+        }
+        if is_key_pressed(QUICKLOAD_KEY) {
+            load.store(true, Ordering::SeqCst);
+        }
+
+        if is_key_pressed(MEMORY_MAP_KEY) {
+            show_memory_map = !show_memory_map;
+        }
+        if show_memory_map {
+            draw_memory_map(&memory_map);
+        }
+
+        // sleep off rest of frame when frame rate is capped
+        if let Some(fps) = config.max_fps {
+            let frame_time = Duration::from_secs(1) / fps.max(1);
+            if let Some(rest) = frame_time.checked_sub(frame_clock.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+
         // println!("FPS: {:.1}", get_fps());
         next_frame().await
     }
 }
+
+/// advance fade of turned off pixels and copy display, gives `None` if display lock
+/// couldn't be taken within `timeout`
+fn fade_and_snapshot(
+    display: &Mutex<Display>,
+    fade_amount: u8,
+    timeout: Duration,
+) -> Option<Display> {
+    let start = Instant::now();
+    let mut display_handle = loop {
+        match display.try_lock() {
+            Ok(handle) => break handle,
+            Err(TryLockError::Poisoned(err)) => break err.into_inner(),
+            Err(TryLockError::WouldBlock) if start.elapsed() < timeout => thread::yield_now(),
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    };
+    display_handle.pixels.iter_mut().for_each(|pixel| {
+        if *pixel < PIXEL_ON && *pixel > PIXEL_OFF {
+            *pixel = pixel.saturating_sub(fade_amount);
+        }
+    });
+    Some(display_handle.clone())
+}
+
+/// per-frame fade that takes turned off pixel to black in `trail_frames` 60 Hz frames
+/// when rendered frame took `frame_time`
+fn trail_fade_amount(trail_frames: u32, frame_time: Duration) -> u8 {
+    let trail_time = trail_frames.max(1) as f32 / TIMERS_FREQ as f32;
+    let amount = PIXEL_PRE_OFF as f32 * frame_time.as_secs_f32() / trail_time;
+    amount.ceil().clamp(1.0, u8::MAX as f32) as u8
+}
+
+/// color of pixel with value `v` at `time` seconds since start
+fn pixel_color(v: u8, config: &RenderConfig, time: f32) -> Color {
+    let fading = v > PIXEL_OFF && v < PIXEL_ON;
+    // gamma correct fade ramp, fully on and off pixels stay as they are
+    let v = if config.gamma != 1.0 {
+        ((v as f32 / u8::MAX as f32).powf(1.0 / config.gamma) * u8::MAX as f32).round() as u8
+    } else {
+        v
+    };
+    if fading && config.fade_style == FadeStyle::HueCycle {
+        let level = v as f32 / u8::MAX as f32;
+        let hue = (level + time * config.hue_speed).fract();
+        let mut color = hsl_to_rgb(hue, 1.0, level * 0.5);
+        color.a = config.alpha as f32 / u8::MAX as f32;
+        color
+    } else {
+        Color::from_rgba(v, v, v, config.alpha)
+    }
+}
+
+/// draw ram as a bar along the bottom of the screen with font, rom, `pc` and `ireg` marked
+fn draw_memory_map(memory_map: &MemoryMap) {
+    let sw = screen_width();
+    let y = screen_height() - MEMORY_MAP_HEIGHT;
+    let byte_w = sw / RAM_SIZE as f32;
+    let region = |from: usize, size: usize, color: Color| {
+        draw_rectangle(
+            from as f32 * byte_w,
+            y,
+            size as f32 * byte_w,
+            MEMORY_MAP_HEIGHT,
+            color,
+        );
+    };
+    region(0, RAM_SIZE, DARKGRAY);
+    region(FONT_LOAD_ADDR, FONT_SIZE, BLUE);
+    region(ROM_LOAD_ADDR, memory_map.rom_size, DARKGREEN);
+
+    let pc = memory_map.pc.load(Ordering::Relaxed);
+    let ireg = memory_map.ireg.load(Ordering::Relaxed);
+    let marker = |addr: u16, color: Color| {
+        let x = addr as f32 * byte_w;
+        draw_line(x, y, x, y + MEMORY_MAP_HEIGHT, 2.0, color);
+    };
+    marker(ireg, YELLOW);
+    marker(pc, RED);
+
+    let radix = memory_map.radix;
+    let legend = format!(
+        "font  rom  pc {}  I {}",
+        radix.format(pc),
+        radix.format(ireg)
+    );
+    draw_text(&legend, 4.0, y - 4.0, 16.0, WHITE);
+}
+
+#[rustfmt::skip]
+pub fn get_default_keymap() -> HashMap<Chip8Key, KeyCode> {
+    use KeyCode as MQ;
+    use Chip8Key as C8;
+    vec![
+        (C8::K1, MQ::Key1), (C8::K2, MQ::Key2), (C8::K3, MQ::Key3), (C8::C, MQ::Key4),
+        (C8::K4,    MQ::Q), (C8::K5,    MQ::W), (C8::K6,    MQ::E), (C8::D,    MQ::R),
+        (C8::K7,    MQ::A), (C8::K8,    MQ::S), (C8::K9,    MQ::D), (C8::E,    MQ::F),
+        (C8::A,     MQ::Z), (C8::K0,    MQ::X), (C8::B,     MQ::C), (C8::F,    MQ::V),
+    ]
+    .into_iter()
+    .collect()
+}
+
+// FIXME: remove if not needed
+// #[rustfmt::skip]
+// pub fn get_default_keymap() -> HashMap<KeyCode, Chip8Key> {
+//     use KeyCode as MQ;
+//     use Chip8Key as C8;
+//     vec![
+//         (MQ::Key1, C8::K1), (MQ::Key2, C8::K2), (MQ::Key3, C8::K3), (MQ::Key4, C8::C),
+//         (MQ::Q,    C8::K4), (MQ::W,    C8::K5), (MQ::E,    C8::K6), (MQ::R,    C8::D),
+//         (MQ::A,    C8::K7), (MQ::S,    C8::K8), (MQ::D,    C8::K9), (MQ::F,    C8::E),
+//         (MQ::Z,    C8::A ), (MQ::X,    C8::K0), (MQ::C,    C8::B ), (MQ::V,    C8::F),
+//     ]
+//     .into_iter()
+//     .collect()
+// }
@@ -0,0 +1,18 @@
+//! CHIP-8 interpreter core, embeddable into other frontends
+//!
+//! The macroquad window, input and sound live behind the `graphics` feature,
+//! build with `default-features = false` to use the core alone.
+pub mod analyze;
+#[cfg(feature = "graphics")]
+pub mod audio;
+pub mod cheat;
+pub mod device;
+#[cfg(feature = "graphics")]
+pub mod graphics;
+pub mod savestate;
+pub mod timing;
+pub mod trace;
+pub mod util;
+
+pub use device::Chip8;
+pub use util::{take_n, take_nn, take_nnn, take_op, take_x, take_y, Chip8Key, ExecError};
@@ -1,11 +1,16 @@
-use device::{decrement_timers_routine, Chip8};
-use graphics::display_draw;
-use macroquad::window::Conf;
-use std::{env, path::PathBuf, str::FromStr, sync::Arc, thread};
+use chip_8_emulator::{
+    analyze, audio,
+    cheat::Cheats,
+    device::{decrement_timers_routine, Chip8, MachineState},
+    graphics::{display_draw, get_default_keymap, MemoryMap, SharedDevice},
+    timing::TimeHistogram,
+    trace::{ControlFlowLog, TraceBuffer},
+};
+use cli::{parse_args, Args};
+use macroquad::{window::Conf, Window};
+use std::{fs::File, io::BufWriter, process, sync::Arc, thread, time::Duration};
 
-mod device;
-mod graphics;
-mod util;
+mod cli;
 
 fn window_conf() -> Conf {
     Conf {
@@ -18,29 +23,161 @@ fn window_conf() -> Conf {
     }
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
+fn main() {
     // read cli args
-    let args = env::args().collect::<Vec<_>>();
-    let rom_path_str = args.get(1).expect("Rom path not provided");
-    let rom_path = PathBuf::from_str(&rom_path_str).expect("Malformed rom path");
+    let args = parse_args();
 
+    // static analysis doesn't need a window
+    if args.analyze {
+        let rom = args.read_rom().unwrap();
+        analyze::print_report(&rom);
+        return;
+    }
+    if let Some(path) = &args.cfg_dot {
+        let rom = args.read_rom().unwrap();
+        let mut out = BufWriter::new(File::create(path).expect("Can't create graph file"));
+        analyze::write_cfg_dot(&rom, &mut out).expect("Can't write graph file");
+        return;
+    }
+
+    // fixed amount of instructions without window, for automated rom testing
+    if let Some(max_instructions) = args.headless {
+        let mut device = create_device(&args);
+        let result = device.run_headless(max_instructions);
+        device.dump_state(args.dump_final_ram);
+        print!("{}", device.display.lock().unwrap().to_text());
+        if result.is_err() {
+            // exit skips destructors, drop device so its files are complete
+            drop(device);
+            process::exit(1);
+        }
+        return;
+    }
+
+    Window::from_config(window_conf(), emulate(args));
+}
+
+/// load rom and apply settings from args
+fn create_device(args: &Args) -> Chip8 {
+    let mut device = Chip8::from_bytes(args.read_rom().unwrap()).unwrap();
+    device.recent = TraceBuffer::new(args.crash_trace);
+    device.radix = args.radix;
+    device.cflog = args.cflog.as_ref().map(|path| {
+        ControlFlowLog::create(path, args.radix).expect("Can't create control flow log")
+    });
+    device.cheats = args
+        .cheats
+        .as_ref()
+        .map(|path| Cheats::load(path).unwrap_or_else(|err| panic!("Can't load cheats: {}", err)));
+    device.set_display_size(args.display_size.0, args.display_size.1);
+    device.set_buffered_display(args.buffered_display);
+    device.set_predecode(args.predecode);
+    device.state_path = args.state_file.clone().or_else(|| {
+        let rom_path = args.rom_path.as_ref().filter(|_| args.rom_hex.is_none())?;
+        Some(rom_path.with_extension("state"))
+    });
+    device.step_mode = args.step;
+    device.quirks = args.quirks;
+    device.strictness = args.strictness;
+    device.warn_once = !args.warn_every;
+    device.warn_uninit_reads = args.warn_uninit;
+    device.warn_font_writes = args.warn_font;
+    device.warn_self_modify = args.warn_smc;
+    device.ips = args.ips;
+    if let Some(seed) = args.seed {
+        device.reseed(seed);
+    }
+    device.ram_latency = args.ram_latency;
+    device.panic_guard = args.panic_guard;
+    device.extensions = args.extensions;
+    device.allowed_ops = args.allowed_ops;
+    device.skip_denied = args.skip_denied;
+    device.queue_key_releases = args.queue_keys;
+    device
+}
+
+async fn emulate(args: Args) {
     // init device
-    let mut device = Chip8::new(rom_path).unwrap();
-    let display = Arc::clone(&device.display);
+    let mut device = create_device(&args);
     let delay_timer = Arc::clone(&device.delay_timer);
     let sound_timer = Arc::clone(&device.sound_timer);
-    let down_keys = device.down_keys.clone();
-    let released_keys = device.released_keys.clone();
-    let keymap = device.keymap.clone();
+    let timers_shutdown = Arc::clone(&device.shutdown);
+    let timers_paused = Arc::clone(&device.paused);
+    let timers_clock = Arc::clone(&device.clock);
+    let beep_timer = Arc::clone(&device.sound_timer);
+    let beep_shutdown = Arc::clone(&device.shutdown);
+    let beep_paused = Arc::clone(&device.paused);
+    let beep_clock = Arc::clone(&device.clock);
+    let shared = SharedDevice {
+        display: Arc::clone(&device.display),
+        down_keys: device.down_keys.clone(),
+        released_keys: device.released_keys.clone(),
+        keymap: get_default_keymap(),
+        shutdown: Arc::clone(&device.shutdown),
+        paused: Arc::clone(&device.paused),
+        step: Arc::clone(&device.step_requests),
+        reset: Arc::clone(&device.reset_requested),
+        turbo: Arc::clone(&device.turbo),
+        save: Arc::clone(&device.save_requested),
+        load: Arc::clone(&device.load_requested),
+        state: Arc::clone(&device.state),
+        collision: Arc::clone(&device.collision),
+        memory_map: MemoryMap {
+            pc: Arc::clone(&device.shared_pc),
+            ireg: Arc::clone(&device.shared_ireg),
+            rom_size: device.rom_size,
+            radix: args.radix,
+        },
+    };
+
+    // optional timing diagnostics
+    let mut frame_histogram = args
+        .frame_histogram
+        .then(|| TimeHistogram::new("Render frame times", Duration::from_micros(500), 64));
+    if args.frame_histogram {
+        device.inst_histogram = Some(TimeHistogram::new(
+            "Instruction times",
+            Duration::from_micros(10),
+            50,
+        ));
+    }
 
     // start threads
-    let timers_thread =
-        thread::spawn(move || decrement_timers_routine(vec![delay_timer, sound_timer]));
-    let device_thread = thread::spawn(move || device.run().unwrap());
+    let timers_thread = thread::spawn(move || {
+        decrement_timers_routine(
+            vec![delay_timer, sound_timer],
+            timers_shutdown,
+            timers_paused,
+            timers_clock,
+        )
+    });
+    let beep_thread = thread::spawn(move || {
+        audio::beep_routine(beep_timer, beep_shutdown, beep_paused, beep_clock)
+    });
+    let device_thread = thread::spawn(move || {
+        // failure is reported by `run` and kept in device state
+        let _ = device.run();
+        device
+    });
 
     // await on execution
-    display_draw(display, down_keys, released_keys, keymap).await;
-    device_thread.join().unwrap();
+    display_draw(shared, args.render, args.input, &mut frame_histogram).await;
+    let device = device_thread.join().unwrap();
     timers_thread.join().unwrap();
+    beep_thread.join().unwrap();
+
+    // report timings collected during execution
+    for histogram in frame_histogram.iter().chain(device.inst_histogram.iter()) {
+        histogram.print();
+    }
+
+    if args.dump_final_state {
+        device.dump_state(args.dump_final_ram);
+    }
+
+    if let MachineState::Error(_) = device.state() {
+        // exit skips destructors, drop device so its files are complete
+        drop(device);
+        process::exit(1);
+    }
 }
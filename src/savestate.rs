@@ -0,0 +1,121 @@
+//! Snapshots of emulator state
+use crate::device::{Display, RAM_SIZE, STACK_SIZE, VREG_SIZE};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+};
+
+/// everything needed to continue execution later, see `Chip8::save_state`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedState {
+    pub pc: u16,
+    pub ireg: u16,
+    /// vec like `ram`, so wrong length is reported by `validate` instead of decoder
+    pub stack: Vec<u16>,
+    pub sp: usize,
+    pub vreg: [u8; VREG_SIZE],
+    /// kept as vec since serde doesn't handle arrays of ram size
+    pub ram: Vec<u8>,
+    pub display: Display,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl SavedState {
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(out, self).map_err(io::Error::other)
+    }
+
+    /// read state written by `write_to_file`, rejects states failing `validate`
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let input = BufReader::new(File::open(path)?);
+        let state: Self = bincode::deserialize_from(input).map_err(io::Error::other)?;
+        state.validate()?;
+        Ok(state)
+    }
+
+    /// file may be corrupt or edited by hand, sizes and pointers must be safe to use,
+    /// `Chip8::load_state` relies on this
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        let Display { w, h, pixels } = &self.display;
+        if *w == 0 || *h == 0 || w.checked_mul(*h) != Some(pixels.len()) {
+            return invalid(format!("Display {}x{} with {} pixels", w, h, pixels.len()));
+        }
+        if self.ram.len() != RAM_SIZE {
+            return invalid(format!("Ram of {} bytes", self.ram.len()));
+        }
+        if self.stack.len() != STACK_SIZE {
+            return invalid(format!("Stack of {} slots", self.stack.len()));
+        }
+        if self.sp > STACK_SIZE {
+            return invalid(format!("Stack pointer {}", self.sp));
+        }
+        if self.pc as usize >= RAM_SIZE {
+            return invalid(format!("Program counter {:#06x}", self.pc));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Chip8;
+
+    fn state() -> SavedState {
+        Chip8::from_bytes(Vec::new()).unwrap().save_state()
+    }
+
+    fn assert_invalid(state: SavedState) {
+        assert_eq!(
+            state.validate().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn saved_state_is_valid() {
+        assert!(state().validate().is_ok());
+    }
+
+    #[test]
+    fn mismatched_display_is_rejected() {
+        let mut state = state();
+        state.display.pixels.pop();
+        assert_invalid(state.clone());
+        state.display = Display::new(0, 32);
+        assert_invalid(state);
+    }
+
+    #[test]
+    fn short_ram_is_rejected() {
+        let mut state = state();
+        state.ram.pop();
+        assert_invalid(state);
+    }
+
+    #[test]
+    fn short_stack_is_rejected() {
+        let mut state = state();
+        state.stack.pop();
+        assert_invalid(state);
+    }
+
+    #[test]
+    fn stack_pointer_past_stack_is_rejected() {
+        let mut state = state();
+        state.sp = STACK_SIZE + 1;
+        assert_invalid(state);
+    }
+
+    #[test]
+    fn pc_outside_ram_is_rejected() {
+        let mut state = state();
+        state.pc = RAM_SIZE as u16;
+        assert_invalid(state);
+    }
+}
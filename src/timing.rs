@@ -0,0 +1,128 @@
+//! Timing diagnostics and time source
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+// width of the widest histogram bar in characters
+const MAX_BAR_WIDTH: usize = 50;
+
+/// fixed-width buckets of measured durations, last bucket collects everything above range
+pub struct TimeHistogram {
+    title: &'static str,
+    bucket_width: Duration,
+    buckets: Vec<u64>,
+}
+
+impl TimeHistogram {
+    pub fn new(title: &'static str, bucket_width: Duration, bucket_count: usize) -> Self {
+        Self {
+            title,
+            bucket_width,
+            buckets: vec![0; bucket_count + 1],
+        }
+    }
+
+    pub fn record(&mut self, time: Duration) {
+        let idx = (time.as_nanos() / self.bucket_width.as_nanos()) as usize;
+        let last = self.buckets.len() - 1;
+        self.buckets[idx.min(last)] += 1;
+    }
+
+    /// print ascii histogram, empty leading and trailing buckets are skipped
+    pub fn print(&self) {
+        let total: u64 = self.buckets.iter().sum();
+        println!("{} ({} samples)", self.title, total);
+        let (first, last) = match (
+            self.buckets.iter().position(|&n| n > 0),
+            self.buckets.iter().rposition(|&n| n > 0),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        let max = self.buckets.iter().copied().max().unwrap_or(1);
+        let width_ms = self.bucket_width.as_secs_f64() * 1000.0;
+        for (i, &n) in self.buckets.iter().enumerate().take(last + 1).skip(first) {
+            let from = i as f64 * width_ms;
+            let range = if i == self.buckets.len() - 1 {
+                format!("{:>8.3} -      inf", from)
+            } else {
+                format!("{:>8.3} - {:>8.3}", from, from + width_ms)
+            };
+            let bar = "#".repeat((n * MAX_BAR_WIDTH as u64 / max) as usize);
+            println!(
+                "{} ms | {:<width$} {}",
+                range,
+                bar,
+                n,
+                width = MAX_BAR_WIDTH
+            );
+        }
+    }
+}
+
+/// source of time for execution and timer loops, replaceable to drive them without wall clock
+pub trait Clock: Send + Sync {
+    /// time passed since clock was created
+    fn now(&self) -> Duration;
+    /// block for given time
+    fn sleep(&self, time: Duration);
+}
+
+/// wall clock time
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, time: Duration) {
+        thread::sleep(time);
+    }
+}
+
+/// time that passes only when advanced by hand or slept on, for deterministic tests
+#[derive(Default)]
+pub struct ManualClock {
+    nanos: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, time: Duration) {
+        self.nanos
+            .fetch_add(time.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+
+    /// returns at once with clock moved forward
+    fn sleep(&self, time: Duration) {
+        self.advance(time);
+    }
+}
@@ -0,0 +1,81 @@
+//! Execution tracing
+use crate::util::{take_op, Radix};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// default amount of instructions kept for post-mortem
+pub const DEFAULT_TRACE_LEN: usize = 32;
+
+/// most recently executed instructions, oldest are dropped first
+pub struct TraceBuffer {
+    capacity: usize,
+    entries: VecDeque<(u16, u16)>,
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// remember instruction `inst` fetched from address `pc`
+    pub fn record(&mut self, pc: u16, inst: u16) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((pc, inst));
+    }
+
+    /// print recorded instructions to stderr, oldest first
+    pub fn dump(&self, radix: Radix) {
+        if self.entries.is_empty() {
+            return;
+        }
+        eprintln!("Last {} executed instructions:", self.entries.len());
+        for (pc, inst) in self.entries.iter() {
+            eprintln!("  {}: {}", radix.format(*pc), radix.format(*inst));
+        }
+    }
+}
+
+/// log of jumps, calls and returns for reconstructing call graph
+pub struct ControlFlowLog {
+    out: BufWriter<File>,
+    radix: Radix,
+}
+
+impl ControlFlowLog {
+    pub fn create<P: AsRef<Path>>(path: P, radix: Radix) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            radix,
+        })
+    }
+
+    /// log `inst` executed at `from` if it changes control flow, `to` is pc after execution
+    pub fn record(&mut self, from: u16, inst: u16, to: u16) -> io::Result<()> {
+        let kind = match (take_op(inst), inst) {
+            (_, 0x00ee) => "return",
+            (0x1, _) => "jump",
+            (0x2, _) => "call",
+            (0xb, _) => "jump_offset",
+            _ => return Ok(()),
+        };
+        let (from, to) = (self.radix.format(from), self.radix.format(to));
+        writeln!(self.out, "{} {:<11} -> {}", from, kind, to)
+    }
+
+    /// write out buffered records, needed before `process::exit` which skips drop
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -1,8 +1,7 @@
 //! Utilities
-use macroquad::prelude::KeyCode;
-use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::str::FromStr;
 
 pub const OP_MASK: u16 = 0xf000;
 pub const X_MASK: u16 = 0x0f00;
@@ -12,6 +11,8 @@ pub const NN_MASK: u16 = 0x00ff;
 pub const NNN_MASK: u16 = 0x0fff;
 
 pub const FONT_CHAR_SIZE: usize = 5;
+/// bytes of whole font, one character per hex digit
+pub const FONT_SIZE: usize = 16 * FONT_CHAR_SIZE;
 
 #[inline]
 pub const fn take_op(inst: u16) -> u8 {
@@ -43,7 +44,33 @@ pub const fn take_nnn(inst: u16) -> u16 {
     inst & NNN_MASK
 }
 
-#[derive(Clone, Copy, Debug)]
+/// instruction split into opcode class and operands once, see `Chip8::set_predecode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub inst: u16,
+    pub op: u8,
+    pub x: u8,
+    pub y: u8,
+    pub n: u8,
+    pub nn: u8,
+    pub nnn: u16,
+}
+
+impl Decoded {
+    pub const fn new(inst: u16) -> Self {
+        Self {
+            inst,
+            op: take_op(inst),
+            x: take_x(inst),
+            y: take_y(inst),
+            n: take_n(inst),
+            nn: take_nn(inst),
+            nnn: take_nnn(inst),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ExecError {
     VRegOutOfBounds,
     StackOverflow,
@@ -53,6 +80,9 @@ pub enum ExecError {
     LoadRomError,
     RamError,
     KeymapError,
+    UnknownInstruction(u16),
+    DeniedInstruction(u16),
+    InternalPanic(u16),
 }
 
 impl Error for ExecError {}
@@ -84,6 +114,47 @@ impl Display for ExecError {
             Self::KeymapError => {
                 write!(f, "Error while mapping key from instruction to keycode")
             }
+            Self::UnknownInstruction(inst) => {
+                write!(f, "Unknown instruction: {:#06x}", inst)
+            }
+            Self::DeniedInstruction(inst) => {
+                write!(f, "Instruction not allowed: {:#06x}", inst)
+            }
+            Self::InternalPanic(pc) => {
+                write!(f, "Emulator panicked executing instruction at {:#06x}", pc)
+            }
+        }
+    }
+}
+
+/// number base used for values in debug output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Radix {
+    #[default]
+    Hex,
+    Dec,
+    Bin,
+}
+
+impl Radix {
+    pub fn format(&self, value: u16) -> String {
+        match self {
+            Self::Hex => format!("{:#06x}", value),
+            Self::Dec => format!("{:>5}", value),
+            Self::Bin => format!("{:#018b}", value),
+        }
+    }
+}
+
+impl FromStr for Radix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Self::Hex),
+            "dec" => Ok(Self::Dec),
+            "bin" => Ok(Self::Bin),
+            _ => Err(format!("Unknown radix: {}", s)),
         }
     }
 }
@@ -109,7 +180,7 @@ pub fn get_default_font() -> Vec<u8> {
     ]
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum Chip8Key {
     K0 = 0,
@@ -130,10 +201,11 @@ pub enum Chip8Key {
     F,
 }
 
+/// only low nibble is used, so register values above 0xF can't crash key opcodes
 impl From<u8> for Chip8Key {
     fn from(byte: u8) -> Self {
         use Chip8Key::*;
-        match byte {
+        match byte & 0x0f {
             0x00 => K0,
             0x01 => K1,
             0x02 => K2,
@@ -149,37 +221,7 @@ impl From<u8> for Chip8Key {
             0x0c => C,
             0x0d => D,
             0x0e => E,
-            0x0f => F,
-            _ => unreachable!("unsupported key conversion"),
+            _ => F,
         }
     }
 }
-
-#[rustfmt::skip]
-pub fn get_default_keymap() -> HashMap<Chip8Key, KeyCode> {
-    use KeyCode as MQ;
-    use Chip8Key as C8;
-    vec![
-        (C8::K1, MQ::Key1), (C8::K2, MQ::Key2), (C8::K3, MQ::Key3), (C8::C, MQ::Key4),
-        (C8::K4,    MQ::Q), (C8::K5,    MQ::W), (C8::K6,    MQ::E), (C8::D,    MQ::R),
-        (C8::K7,    MQ::A), (C8::K8,    MQ::S), (C8::K9,    MQ::D), (C8::E,    MQ::F),
-        (C8::A,     MQ::Z), (C8::K0,    MQ::X), (C8::B,     MQ::C), (C8::F,    MQ::V),
-    ]
-    .into_iter()
-    .collect()
-}
-
-// FIXME: remove if not needed
-// #[rustfmt::skip]
-// pub fn get_default_keymap() -> HashMap<KeyCode, Chip8Key> {
-//     use KeyCode as MQ;
-//     use Chip8Key as C8;
-//     vec![
-//         (MQ::Key1, C8::K1), (MQ::Key2, C8::K2), (MQ::Key3, C8::K3), (MQ::Key4, C8::C),
-//         (MQ::Q,    C8::K4), (MQ::W,    C8::K5), (MQ::E,    C8::K6), (MQ::R,    C8::D),
-//         (MQ::A,    C8::K7), (MQ::S,    C8::K8), (MQ::D,    C8::K9), (MQ::F,    C8::E),
-//         (MQ::Z,    C8::A ), (MQ::X,    C8::K0), (MQ::C,    C8::B ), (MQ::V,    C8::F),
-//     ]
-//     .into_iter()
-//     .collect()
-// }